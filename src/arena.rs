@@ -0,0 +1,75 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::OnceLock;
+
+// the first segment holds 2^FIRST_BITS items and every further one twice as
+// many as the one before, which is enough segments to address any u32 index
+const FIRST_BITS: u32 = 10;
+const SEGMENTS: usize = (u32::BITS - FIRST_BITS + 1) as usize;
+
+/// Where the item at `index` lives: its segment and the offset within it.
+fn locate(index: u32) -> (usize, usize) {
+    let position = index as u64 + (1 << FIRST_BITS);
+    let segment = (u64::BITS - 1 - position.leading_zeros() - FIRST_BITS) as usize;
+    (segment, (position - (1 << (segment as u32 + FIRST_BITS))) as usize)
+}
+
+/// Append-only storage addressed by `u32` index that search threads can push
+/// to and read from concurrently. Items live in segments that double in size,
+/// so growing never moves an item another thread may be looking at.
+pub struct Arena<T> {
+    segments: [OnceLock<Box<[OnceLock<T>]>>; SEGMENTS],
+    len: AtomicU32,
+}
+
+impl<T> Arena<T> {
+    pub fn new() -> Self {
+        Self {
+            segments: std::array::from_fn(|_| OnceLock::new()),
+            len: AtomicU32::new(0),
+        }
+    }
+
+    /// Store `item` and return its index.
+    pub fn push(&self, item: T) -> u32 {
+        let index = self.len.fetch_add(1, Ordering::Relaxed);
+        assert!(index != u32::MAX, "arena is full");
+
+        let (segment, offset) = locate(index);
+        let size = 1usize << (segment as u32 + FIRST_BITS);
+        let segment = self.segments[segment].get_or_init(|| (0..size).map(|_| OnceLock::new()).collect());
+        if segment[offset].set(item).is_err() {
+            unreachable!("arena slot {} filled twice", index);
+        }
+
+        index
+    }
+
+    /// The item at `index`, which must have been returned by `push` on a
+    /// thread this one has synchronized with since.
+    pub fn get(&self, index: u32) -> &T {
+        let (segment, offset) = locate(index);
+        self.segments[segment]
+            .get()
+            .and_then(|segment| segment[offset].get())
+            .expect("index of an item not in the arena")
+    }
+
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed) as usize
+    }
+
+    /// How many items the segments allocated so far can hold.
+    pub fn capacity(&self) -> usize {
+        self.segments
+            .iter()
+            .filter_map(|segment| segment.get())
+            .map(|segment| segment.len())
+            .sum()
+    }
+}
+
+impl<T> Default for Arena<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,129 @@
+use chess::ChessMove;
+use lru::LruCache;
+
+use std::mem::size_of;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// The legal moves with their normalized priors, and the value head output.
+pub type Evaluation = (Vec<(ChessMove, f32)>, f32);
+
+// rough bookkeeping cost of one entry in the lru list and its hash map
+const ENTRY_OVERHEAD: usize = 64;
+
+/// Least-recently-used cache of network evaluations keyed by position hash,
+/// bounded by an approximate size in bytes.
+pub struct NNCache {
+    entries: Mutex<Entries>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+struct Entries {
+    lru: LruCache<u64, Evaluation>,
+    size: usize,
+    capacity: usize,
+}
+
+impl Entries {
+    fn evict(&mut self) {
+        while self.size > self.capacity {
+            match self.lru.pop_lru() {
+                Some((_, evaluation)) => self.size -= entry_size(&evaluation),
+                None => break,
+            }
+        }
+    }
+}
+
+fn entry_size(evaluation: &Evaluation) -> usize {
+    size_of::<u64>()
+        + size_of::<Evaluation>()
+        + evaluation.0.len() * size_of::<(ChessMove, f32)>()
+        + ENTRY_OVERHEAD
+}
+
+/// Scale the priors of the legal moves so that they sum to one.
+pub fn normalize(probabilities: &mut [(ChessMove, f32)]) {
+    let total: f32 = probabilities.iter().map(|p| p.1).sum();
+    if total > 0.0 {
+        for probability in probabilities.iter_mut() {
+            probability.1 /= total;
+        }
+    }
+}
+
+impl NNCache {
+    pub fn new(size_mb: usize) -> Self {
+        Self {
+            entries: Mutex::new(Entries {
+                lru: LruCache::unbounded(),
+                size: 0,
+                capacity: size_mb * 1024 * 1024,
+            }),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    /// Change the size limit, evicting the oldest entries if it shrank.
+    pub fn resize(&self, size_mb: usize) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.capacity = size_mb * 1024 * 1024;
+        entries.evict();
+    }
+
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.lru.clear();
+        entries.size = 0;
+    }
+
+    pub fn get(&self, hash: u64) -> Option<Evaluation> {
+        let evaluation = self.entries.lock().unwrap().lru.get(&hash).cloned();
+        if evaluation.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+
+        evaluation
+    }
+
+    pub fn insert(&self, hash: u64, evaluation: Evaluation) {
+        let mut entries = self.entries.lock().unwrap();
+        let size = entry_size(&evaluation);
+        if size > entries.capacity {
+            return;
+        }
+
+        if let Some(old) = entries.lru.put(hash, evaluation) {
+            entries.size -= entry_size(&old);
+        }
+        entries.size += size;
+        entries.evict();
+    }
+
+    pub fn reset_stats(&self) {
+        self.hits.store(0, Ordering::Relaxed);
+        self.misses.store(0, Ordering::Relaxed);
+    }
+
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> usize {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Fraction of lookups since the last `reset_stats` that were answered from the cache.
+    pub fn hit_rate(&self) -> f32 {
+        let lookups = self.hits() + self.misses();
+        if lookups == 0 {
+            0.0
+        } else {
+            self.hits() as f32 / lookups as f32
+        }
+    }
+}
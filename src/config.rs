@@ -1,4 +1,64 @@
+/// Network used when neither the command line nor the environment names one.
 pub const MODEL: &str = "Net_20x256_temp_2.2.pt";
+/// Environment variable holding the path of the network to load.
+pub const WEIGHTS_ENV: &str = "DIVINE_WEIGHTS";
 
-#[cfg(feature = "use-external-eval")]
+/// Number of leaves each search thread collects before a network call.
+pub const DEFAULT_BATCH_SIZE: usize = 8;
+pub const MAX_BATCH_SIZE: usize = 1024;
+
+/// Exploration constant of the PUCT formula.
+pub const DEFAULT_CPUCT: f32 = 1.25;
+
+/// Time in ms kept back from every move for communication with the GUI.
+pub const DEFAULT_MOVE_OVERHEAD: usize = 100;
+pub const MAX_MOVE_OVERHEAD: usize = 5000;
+
+/// Stop searching once the best root move can no longer be overtaken in the time left.
+pub const DEFAULT_SMART_PRUNING: bool = true;
+
+/// Positions searched by `bench`, and how many rollouts each.
+pub const BENCH_POSITIONS: &[&str] = &[
+    "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+    "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+    "r1bq1rk1/pp2bppp/2n1pn2/3p4/2PP4/2N1PN2/PP3PPP/R2QKB1R w KQ - 0 8",
+    "8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1",
+];
+pub const BENCH_NODES: usize = 20000;
+
+/// How many moves past the previous search's root we look for a subtree to reuse.
+pub const MAX_REUSE_PLIES: usize = 2;
+
+pub const DEFAULT_THREADS: usize = 1;
+pub const MAX_THREADS: usize = 256;
+
+/// Intra-op threads of libtorch, where 0 keeps the libtorch default.
+pub const DEFAULT_TORCH_THREADS: usize = 0;
+
+/// Share nodes between move orders that reach the same position.
+pub const DEFAULT_TRANSPOSITIONS: bool = false;
+
+/// Memory the search tree may grow to, in MB.
+pub const DEFAULT_HASH: usize = 1024;
+pub const MAX_HASH: usize = 1 << 20;
+
+/// Size of the network evaluation cache, in MB.
+pub const DEFAULT_NN_CACHE_SIZE: usize = 200;
+pub const MAX_NN_CACHE_SIZE: usize = 65536;
+
+/// Number of root moves reported with their own principal variation.
+pub const DEFAULT_MULTIPV: usize = 1;
+pub const MAX_MULTIPV: usize = 256;
+
+/// UCI engine asked for the value of a position by the external evaluator.
 pub const ENGINE: &str = "stockfish";
+/// Limits of the external engine's search, where 0 leaves a limit out.
+pub const DEFAULT_EXTERNAL_MOVETIME: usize = 25;
+pub const DEFAULT_EXTERNAL_NODES: usize = 0;
+pub const DEFAULT_EXTERNAL_DEPTH: usize = 0;
+/// Percentage of the leaf value taken from the external engine, and of the
+/// leaves it is asked about at all.
+pub const DEFAULT_EXTERNAL_WEIGHT: usize = 100;
+pub const DEFAULT_EXTERNAL_FRACTION: usize = 100;
+/// How long the external engine may take to answer, in ms, beyond its movetime.
+pub const EXTERNAL_TIMEOUT: u64 = 5000;
@@ -0,0 +1,734 @@
+use crate::cache::Evaluation;
+use crate::encoding::{encode_lc0_positions, NetworkFormat};
+use crate::export;
+use crate::history::History;
+use crate::lc0::{evaluations, ConvWeights, Weights, POLICY_OUTPUTS};
+
+use ndarray::{s, Array1, Array2, Array3, ArrayView2, Axis};
+
+// Activations are [channels, batch, 64], so that the channels of the whole
+// batch form one matrix and a convolution is a single matrix product.
+
+struct Conv {
+    // [outputs, inputs * size * size]
+    weights: Array2<f32>,
+    biases: Array1<f32>,
+    size: usize,
+}
+
+impl Conv {
+    fn new(conv: &ConvWeights, inputs: usize) -> Result<Self, String> {
+        let outputs = conv.biases.len();
+        let size = ((conv.weights.len() / (outputs * inputs)) as f64).sqrt() as usize;
+        if size * size * outputs * inputs != conv.weights.len() {
+            return Err(format!("convolution with {} inputs has the wrong number of weights", inputs));
+        }
+
+        Ok(Self {
+            weights: Array2::from_shape_vec((outputs, inputs * size * size), conv.weights.clone()).unwrap(),
+            biases: Array1::from(conv.biases.clone()),
+            size,
+        })
+    }
+
+    fn outputs(&self) -> usize {
+        self.biases.len()
+    }
+
+    /// Convolve over the board with zero padding, keeping it 8x8.
+    fn forward(&self, x: &Array3<f32>) -> Array3<f32> {
+        let (channels, batch, _) = x.dim();
+        let x = x.view().into_shape((channels, batch * 64)).unwrap();
+
+        let mut y = if self.size == 1 {
+            self.weights.dot(&x)
+        } else {
+            // every output square gets the input squares under the kernel as a column
+            let pad = (self.size / 2) as isize;
+            let mut columns = Array2::<f32>::zeros((channels * self.size * self.size, batch * 64));
+            for c in 0..channels {
+                for ky in 0..self.size {
+                    for kx in 0..self.size {
+                        let mut row = columns.row_mut((c * self.size + ky) * self.size + kx);
+                        let (dy, dx) = (ky as isize - pad, kx as isize - pad);
+                        for b in 0..batch {
+                            for sq in 0..64 {
+                                let (rank, file) = ((sq / 8) as isize + dy, (sq % 8) as isize + dx);
+                                if (0..8).contains(&rank) && (0..8).contains(&file) {
+                                    row[b * 64 + sq] = x[[c, b * 64 + (rank * 8 + file) as usize]];
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            self.weights.dot(&columns)
+        };
+
+        y += &self.biases.view().insert_axis(Axis(1));
+        y.into_shape((self.outputs(), batch, 64)).unwrap()
+    }
+}
+
+struct Linear {
+    // [outputs, inputs]
+    weights: Array2<f32>,
+    biases: Array1<f32>,
+}
+
+impl Linear {
+    fn new(weights: &[f32], biases: &[f32], inputs: usize) -> Result<Self, String> {
+        if biases.is_empty() || weights.len() != biases.len() * inputs {
+            return Err(format!("fully connected layer with {} inputs has the wrong number of weights", inputs));
+        }
+
+        Ok(Self {
+            weights: Array2::from_shape_vec((biases.len(), inputs), weights.to_vec()).unwrap(),
+            biases: Array1::from(biases.to_vec()),
+        })
+    }
+
+    /// Apply the layer to each row of `x`.
+    fn forward(&self, x: ArrayView2<f32>) -> Array2<f32> {
+        x.dot(&self.weights.t()) + &self.biases
+    }
+}
+
+fn relu(mut x: Array3<f32>) -> Array3<f32> {
+    x.mapv_inplace(|value| value.max(0.0));
+    x
+}
+
+/// Flatten the activations to a row per position, channel by channel.
+fn flatten(x: &Array3<f32>) -> Array2<f32> {
+    let (channels, batch, _) = x.dim();
+    x.view()
+        .permuted_axes([1, 0, 2])
+        .as_standard_layout()
+        .into_owned()
+        .into_shape((batch, channels * 64))
+        .unwrap()
+}
+
+struct Se {
+    fc1: Linear,
+    fc2: Linear,
+}
+
+impl Se {
+    /// Scale and shift the channels of `x` by what the unit makes of their averages.
+    fn forward(&self, mut x: Array3<f32>) -> Array3<f32> {
+        let channels = x.dim().0;
+        let pooled = x.mean_axis(Axis(2)).unwrap().reversed_axes();
+        let mut out = self.fc2.forward(self.fc1.forward(pooled.view()).mapv(|value| value.max(0.0)).view());
+        out.slice_mut(s![.., ..channels]).mapv_inplace(|value| 1.0 / (1.0 + (-value).exp()));
+
+        for (c, mut channel) in x.outer_iter_mut().enumerate() {
+            for (b, mut squares) in channel.outer_iter_mut().enumerate() {
+                let (gamma, beta) = (out[[b, c]], out[[b, channels + c]]);
+                squares.mapv_inplace(|value| value * gamma + beta);
+            }
+        }
+
+        x
+    }
+}
+
+struct Residual {
+    conv1: Conv,
+    conv2: Conv,
+    se: Option<Se>,
+}
+
+/// What a network takes as input and how its policy is read.
+#[derive(Clone, Copy)]
+enum Format {
+    /// Lc0's 112 planes and 1858 policy outputs.
+    Lc0,
+    /// One of the engine's own formats, exported from a TorchScript module.
+    Exported(NetworkFormat),
+}
+
+impl Format {
+    fn inputs(self) -> usize {
+        match self {
+            Format::Lc0 => 112,
+            Format::Exported(format) => format.input.planes(),
+        }
+    }
+
+    fn policy_outputs(self) -> usize {
+        match self {
+            Format::Lc0 => POLICY_OUTPUTS,
+            Format::Exported(format) => format.policy.planes() * 64,
+        }
+    }
+}
+
+/// An Lc0 network, or an exported one of the engine's own format, run in plain
+/// Rust on the CPU, without libtorch.
+pub struct Network {
+    input: Conv,
+    residual: Vec<Residual>,
+    policy: Conv,
+    policy_fc: Linear,
+    value: Conv,
+    value_fc1: Linear,
+    value_fc2: Linear,
+    wdl: bool,
+    format: Format,
+}
+
+impl Network {
+    pub fn new(weights: &Weights) -> Result<Self, String> {
+        Self::build(weights, Format::Lc0)
+    }
+
+    pub fn exported(weights: &export::Weights) -> Result<Self, String> {
+        Self::build(&weights.network, Format::Exported(weights.format))
+    }
+
+    fn build(weights: &Weights, format: Format) -> Result<Self, String> {
+        let input = Conv::new(&weights.input, format.inputs())?;
+        let filters = input.outputs();
+        let residual = weights
+            .residual
+            .iter()
+            .map(|block| {
+                let se = match &block.se {
+                    Some(se) => Some(Se {
+                        fc1: Linear::new(&se.w1, &se.b1, filters)?,
+                        fc2: Linear::new(&se.w2, &se.b2, se.b1.len())?,
+                    }),
+                    None => None,
+                };
+                Ok(Residual {
+                    conv1: Conv::new(&block.conv1, filters)?,
+                    conv2: Conv::new(&block.conv2, filters)?,
+                    se,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let policy = Conv::new(&weights.policy, filters)?;
+        let policy_fc = Linear::new(&weights.ip_pol_w, &weights.ip_pol_b, policy.outputs() * 64)?;
+        if weights.ip_pol_b.len() != format.policy_outputs() {
+            return Err(format!(
+                "policy head has {} outputs instead of {}",
+                weights.ip_pol_b.len(),
+                format.policy_outputs()
+            ));
+        }
+        let value = Conv::new(&weights.value, filters)?;
+        let value_fc1 = Linear::new(&weights.ip1_val_w, &weights.ip1_val_b, value.outputs() * 64)?;
+        let value_fc2 = Linear::new(&weights.ip2_val_w, &weights.ip2_val_b, weights.ip1_val_b.len())?;
+        if value_fc2.biases.len() != if weights.wdl { 3 } else { 1 } {
+            return Err(format!("value head has {} outputs", value_fc2.biases.len()));
+        }
+
+        Ok(Self {
+            input,
+            residual,
+            policy,
+            policy_fc,
+            value,
+            value_fc1,
+            value_fc2,
+            wdl: weights.wdl,
+            format,
+        })
+    }
+
+    /// The policy logits, a row per position, and the values for a batch of encoded positions.
+    fn forward(&self, planes: Array3<f32>) -> (Array2<f32>, Array1<f32>) {
+        let mut x = relu(self.input.forward(&planes));
+        for block in &self.residual {
+            let mut y = block.conv2.forward(&relu(block.conv1.forward(&x)));
+            if let Some(se) = &block.se {
+                y = se.forward(y);
+            }
+            x = relu(y + x);
+        }
+
+        let policy = self.policy_fc.forward(flatten(&relu(self.policy.forward(&x))).view());
+        let value = flatten(&relu(self.value.forward(&x)));
+        let value = self.value_fc1.forward(value.view()).mapv(|value| value.max(0.0));
+        let value = self.value_fc2.forward(value.view());
+        let value = if self.wdl {
+            value
+                .outer_iter()
+                .map(|wdl| {
+                    let max = wdl.fold(f32::MIN, |max, value| max.max(*value));
+                    let exp = wdl.mapv(|value| (value - max).exp());
+                    (exp[0] - exp[2]) / exp.sum()
+                })
+                .collect()
+        } else {
+            value.column(0).mapv(f32::tanh)
+        };
+
+        (policy, value)
+    }
+
+    pub fn evaluate(&self, positions: &[History]) -> Vec<Evaluation> {
+        let planes = match self.format {
+            Format::Lc0 => encode_lc0_positions(positions),
+            Format::Exported(format) => format.input.encode(positions),
+        };
+        let (batch, inputs) = (planes.dim().0, planes.dim().1);
+        let planes = planes
+            .permuted_axes([1, 0, 2, 3])
+            .as_standard_layout()
+            .into_owned()
+            .into_shape((inputs, batch, 64))
+            .unwrap();
+
+        let (policy, value) = self.forward(planes);
+        let (policy, value) = (policy.as_slice().unwrap(), value.as_slice().unwrap());
+        match self.format {
+            Format::Lc0 => evaluations(positions, policy, value),
+            Format::Exported(format) => export::evaluations(positions, policy, value, format.policy),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::{InputEncoding, PolicyLayout};
+    #[cfg(feature = "torch")]
+    use crate::encoding::get_neural_output_batched_with;
+    use crate::export::tests::encode;
+    use crate::lc0::tests::{field, layer, net, quantize};
+
+    use chess::*;
+
+    #[cfg(feature = "torch")]
+    use tch::jit::IValue;
+
+    #[cfg(feature = "torch")]
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    /// A tensor of an exported weights file: its name, shape and values.
+    type Tensor = (String, Vec<usize>, Vec<f32>);
+
+    /// Deterministic values in [-scale, scale), rounded to what a weights file can hold.
+    fn values(len: usize, seed: &mut u64, scale: f32) -> Vec<f32> {
+        let values = (0..len)
+            .map(|_| {
+                *seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                ((*seed >> 40) as f32 / (1u64 << 24) as f32 * 2.0 - 1.0) * scale
+            })
+            .collect::<Vec<_>>();
+        quantize(&values)
+    }
+
+    fn linear(weights: &[f32], biases: &[f32], x: &[f32]) -> Vec<f32> {
+        biases
+            .iter()
+            .enumerate()
+            .map(|(o, bias)| bias + x.iter().enumerate().map(|(i, value)| weights[o * x.len() + i] * value).sum::<f32>())
+            .collect()
+    }
+
+    /// A convolution block as a weights file stores it, with batch norm not yet folded in.
+    struct Block {
+        weights: Vec<f32>,
+        biases: Vec<f32>,
+        means: Vec<f32>,
+        variances: Vec<f32>,
+        gammas: Vec<f32>,
+        betas: Vec<f32>,
+        size: usize,
+    }
+
+    impl Block {
+        fn new(outputs: usize, inputs: usize, size: usize, seed: &mut u64) -> Self {
+            let scale = 1.0 / ((inputs * size * size) as f32).sqrt();
+            Self {
+                weights: values(outputs * inputs * size * size, seed, scale),
+                biases: values(outputs, seed, 0.1),
+                means: values(outputs, seed, 0.1),
+                variances: values(outputs, seed, 0.5).iter().map(|value| value + 1.0).collect(),
+                gammas: values(outputs, seed, 0.5).iter().map(|value| value + 1.0).collect(),
+                betas: values(outputs, seed, 0.1),
+                size,
+            }
+        }
+
+        fn encode(&self, number: u64) -> Vec<u8> {
+            let layers = [&self.weights, &self.biases, &self.means, &self.variances, &self.gammas, &self.betas];
+            let block = layers
+                .iter()
+                .enumerate()
+                .flat_map(|(i, values)| layer(i as u64 + 1, values))
+                .collect::<Vec<_>>();
+            field(number, &block)
+        }
+
+        /// The block as the convolution `conv` and the batch norm `bn` of an exported weights file.
+        fn tensors(&self, conv: &str, bn: &str) -> Vec<Tensor> {
+            let outputs = self.biases.len();
+            let inputs = self.weights.len() / (outputs * self.size * self.size);
+            let vector = |name: String, values: &Vec<f32>| (name, vec![outputs], values.clone());
+            vec![
+                (format!("{}.weight", conv), vec![outputs, inputs, self.size, self.size], self.weights.clone()),
+                vector(format!("{}.bias", conv), &self.biases),
+                vector(format!("{}.running_mean", bn), &self.means),
+                vector(format!("{}.running_var", bn), &self.variances),
+                vector(format!("{}.weight", bn), &self.gammas),
+                vector(format!("{}.bias", bn), &self.betas),
+            ]
+        }
+
+        /// Convolve the planes of `x` square by square, then normalize.
+        fn forward(&self, x: &[Vec<f32>]) -> Vec<Vec<f32>> {
+            let (size, pad) = (self.size, self.size as isize / 2);
+            (0..self.biases.len())
+                .map(|o| {
+                    let scale = self.gammas[o] / (self.variances[o] + 1e-5).sqrt();
+                    (0..64)
+                        .map(|sq| {
+                            let mut sum = self.biases[o];
+                            for (i, plane) in x.iter().enumerate() {
+                                for ky in 0..size {
+                                    for kx in 0..size {
+                                        let rank = (sq / 8) as isize + ky as isize - pad;
+                                        let file = (sq % 8) as isize + kx as isize - pad;
+                                        if (0..8).contains(&rank) && (0..8).contains(&file) {
+                                            let weight = self.weights[((o * x.len() + i) * size + ky) * size + kx];
+                                            sum += weight * plane[(rank * 8 + file) as usize];
+                                        }
+                                    }
+                                }
+                            }
+                            (sum - self.means[o]) * scale + self.betas[o]
+                        })
+                        .collect()
+                })
+                .collect()
+        }
+    }
+
+    fn relu(x: Vec<Vec<f32>>) -> Vec<Vec<f32>> {
+        x.into_iter()
+            .map(|plane| plane.into_iter().map(|value| value.max(0.0)).collect())
+            .collect()
+    }
+
+    /// A small SE network, written out the way a weights file holds it and
+    /// evaluated one position and one square at a time.
+    struct Reference {
+        input: Block,
+        residual: Vec<(Block, Block, [Vec<f32>; 4])>,
+        policy: Block,
+        policy_fc: [Vec<f32>; 2],
+        value: Block,
+        value_fc1: [Vec<f32>; 2],
+        value_fc2: [Vec<f32>; 2],
+        wdl: bool,
+    }
+
+    impl Reference {
+        fn new(wdl: bool) -> Self {
+            let (filters, se, outputs) = (8, 2, if wdl { 3 } else { 1 });
+            let seed = &mut 1;
+            Self {
+                input: Block::new(filters, 112, 3, seed),
+                residual: (0..2)
+                    .map(|_| {
+                        let conv1 = Block::new(filters, filters, 3, seed);
+                        let conv2 = Block::new(filters, filters, 3, seed);
+                        let se = [
+                            values(se * filters, seed, 0.25),
+                            values(se, seed, 0.1),
+                            values(2 * filters * se, seed, 0.5),
+                            values(2 * filters, seed, 0.1),
+                        ];
+                        (conv1, conv2, se)
+                    })
+                    .collect(),
+                policy: Block::new(4, filters, 1, seed),
+                policy_fc: [values(POLICY_OUTPUTS * 4 * 64, seed, 0.05), values(POLICY_OUTPUTS, seed, 0.1)],
+                value: Block::new(2, filters, 1, seed),
+                value_fc1: [values(16 * 2 * 64, seed, 0.1), values(16, seed, 0.1)],
+                value_fc2: [values(outputs * 16, seed, 0.25), values(outputs, seed, 0.1)],
+                wdl,
+            }
+        }
+
+        fn encode(&self) -> Vec<u8> {
+            let mut weights = self.input.encode(1);
+            for (conv1, conv2, se) in &self.residual {
+                let se = se.iter().enumerate().flat_map(|(i, values)| layer(i as u64 + 1, values));
+                let block = [conv1.encode(1), conv2.encode(2), field(3, &se.collect::<Vec<_>>())].concat();
+                weights.extend(field(2, &block));
+            }
+            weights.extend(self.policy.encode(3));
+            weights.extend(layer(4, &self.policy_fc[0]));
+            weights.extend(layer(5, &self.policy_fc[1]));
+            weights.extend(self.value.encode(6));
+            for (i, values) in self.value_fc1.iter().chain(&self.value_fc2).enumerate() {
+                weights.extend(layer(i as u64 + 7, values));
+            }
+            net(self.wdl, &weights)
+        }
+
+        fn evaluate(&self, positions: &[History]) -> Vec<Evaluation> {
+            let planes = encode_lc0_positions(positions);
+            let (mut policy, mut value) = (vec![], vec![]);
+            for planes in planes.outer_iter() {
+                let planes = planes.outer_iter().map(|plane| plane.iter().copied().collect()).collect::<Vec<_>>();
+                let mut x = relu(self.input.forward(&planes));
+                for (conv1, conv2, [w1, b1, w2, b2]) in &self.residual {
+                    let y = conv2.forward(&relu(conv1.forward(&x)));
+                    let pooled = y.iter().map(|plane| plane.iter().sum::<f32>() / 64.0).collect::<Vec<_>>();
+                    let hidden = linear(w1, b1, &pooled).into_iter().map(|value| value.max(0.0)).collect::<Vec<_>>();
+                    let out = linear(w2, b2, &hidden);
+                    let channels = y.len();
+                    x = y
+                        .iter()
+                        .zip(&x)
+                        .enumerate()
+                        .map(|(c, (y, x))| {
+                            let gamma = 1.0 / (1.0 + (-out[c]).exp());
+                            y.iter().zip(x).map(|(y, x)| (y * gamma + out[channels + c] + x).max(0.0)).collect()
+                        })
+                        .collect();
+                }
+
+                let flat = relu(self.policy.forward(&x)).concat();
+                policy.extend(linear(&self.policy_fc[0], &self.policy_fc[1], &flat));
+                let flat = relu(self.value.forward(&x)).concat();
+                let hidden = linear(&self.value_fc1[0], &self.value_fc1[1], &flat);
+                let hidden = hidden.into_iter().map(|value| value.max(0.0)).collect::<Vec<_>>();
+                let out = linear(&self.value_fc2[0], &self.value_fc2[1], &hidden);
+                value.push(if self.wdl {
+                    let exp = out.iter().map(|value| value.exp()).collect::<Vec<_>>();
+                    (exp[0] - exp[2]) / exp.iter().sum::<f32>()
+                } else {
+                    out[0].tanh()
+                });
+            }
+
+            evaluations(positions, &policy, &value)
+        }
+    }
+
+    fn linear_tensors(name: &str, [weights, biases]: &[Vec<f32>; 2]) -> Vec<Tensor> {
+        let outputs = biases.len();
+        vec![
+            (format!("{}.weight", name), vec![outputs, weights.len() / outputs], weights.clone()),
+            (format!("{}.bias", name), vec![outputs], biases.clone()),
+        ]
+    }
+
+    /// A small network of the engine's own format, written out as an exported
+    /// weights file and evaluated one position and one square at a time.
+    struct ExportedReference {
+        format: NetworkFormat,
+        input: Block,
+        residual: Vec<(Block, Block)>,
+        policy: Block,
+        policy_fc: [Vec<f32>; 2],
+        value: Block,
+        value_fc1: [Vec<f32>; 2],
+        value_fc2: [Vec<f32>; 2],
+    }
+
+    impl ExportedReference {
+        fn new(format: NetworkFormat) -> Self {
+            let (filters, entries) = (8, format.policy.planes() * 64);
+            let seed = &mut 2;
+            Self {
+                format,
+                input: Block::new(filters, format.input.planes(), 3, seed),
+                residual: (0..2)
+                    .map(|_| (Block::new(filters, filters, 3, seed), Block::new(filters, filters, 3, seed)))
+                    .collect(),
+                policy: Block::new(4, filters, 1, seed),
+                policy_fc: [values(entries * 4 * 64, seed, 0.05), values(entries, seed, 0.1)],
+                value: Block::new(2, filters, 1, seed),
+                value_fc1: [values(16 * 2 * 64, seed, 0.1), values(16, seed, 0.1)],
+                value_fc2: [values(16, seed, 0.25), values(1, seed, 0.1)],
+            }
+        }
+
+        fn tensors(&self) -> Vec<Tensor> {
+            let mut tensors = self.input.tensors("input.conv", "input.bn");
+            for (i, (conv1, conv2)) in self.residual.iter().enumerate() {
+                tensors.extend(conv1.tensors(&format!("residual.{}.conv1", i), &format!("residual.{}.bn1", i)));
+                tensors.extend(conv2.tensors(&format!("residual.{}.conv2", i), &format!("residual.{}.bn2", i)));
+            }
+            tensors.extend(self.policy.tensors("policy.conv", "policy.bn"));
+            tensors.extend(linear_tensors("policy.fc", &self.policy_fc));
+            tensors.extend(self.value.tensors("value.conv", "value.bn"));
+            tensors.extend(linear_tensors("value.fc1", &self.value_fc1));
+            tensors.extend(linear_tensors("value.fc2", &self.value_fc2));
+            tensors
+        }
+
+        fn evaluate(&self, positions: &[History]) -> Vec<Evaluation> {
+            let planes = self.format.input.encode(positions);
+            let (mut policy, mut value) = (vec![], vec![]);
+            for planes in planes.outer_iter() {
+                let planes = planes.outer_iter().map(|plane| plane.iter().copied().collect()).collect::<Vec<_>>();
+                let mut x = relu(self.input.forward(&planes));
+                for (conv1, conv2) in &self.residual {
+                    let y = conv2.forward(&relu(conv1.forward(&x)));
+                    x = y
+                        .iter()
+                        .zip(&x)
+                        .map(|(y, x)| y.iter().zip(x).map(|(y, x)| (y + x).max(0.0)).collect())
+                        .collect();
+                }
+
+                let flat = relu(self.policy.forward(&x)).concat();
+                policy.extend(linear(&self.policy_fc[0], &self.policy_fc[1], &flat));
+                let flat = relu(self.value.forward(&x)).concat();
+                let hidden = linear(&self.value_fc1[0], &self.value_fc1[1], &flat);
+                let hidden = hidden.into_iter().map(|value| value.max(0.0)).collect::<Vec<_>>();
+                value.push(linear(&self.value_fc2[0], &self.value_fc2[1], &hidden)[0].tanh());
+            }
+
+            export::evaluations(positions, &policy, &value, self.format.policy)
+        }
+
+        /// The network in the tch ops of the TorchScript module the engine
+        /// loads: the same layers, with batch norm, taking the encoded positions
+        /// and legal move masks and giving a softmax over the legal moves.
+        #[cfg(feature = "torch")]
+        fn torch_forward(&self, inputs: &[IValue]) -> IValue {
+            let [IValue::Tensor(positions), IValue::Tensor(masks)] = inputs else {
+                panic!("the module takes the positions and the masks");
+            };
+            let tensors = self
+                .tensors()
+                .into_iter()
+                .map(|(name, shape, values)| {
+                    let shape = shape.iter().map(|dim| *dim as i64).collect::<Vec<_>>();
+                    (name, tch::Tensor::of_slice(&values).reshape(&shape))
+                })
+                .collect::<HashMap<_, _>>();
+            let tensor = |layer: &str, parameter: &str| &tensors[&format!("{}.{}", layer, parameter)];
+            let conv = |x: &tch::Tensor, conv: &str, bn: &str| {
+                let padding = tensor(conv, "weight").size()[2] / 2;
+                x.conv2d(tensor(conv, "weight"), Some(tensor(conv, "bias")), &[1, 1], &[padding, padding], &[1, 1], 1)
+                    .batch_norm(
+                        Some(tensor(bn, "weight")),
+                        Some(tensor(bn, "bias")),
+                        Some(tensor(bn, "running_mean")),
+                        Some(tensor(bn, "running_var")),
+                        false,
+                        0.1,
+                        1e-5,
+                        false,
+                    )
+            };
+            let linear = |x: &tch::Tensor, name: &str| x.linear(tensor(name, "weight"), Some(tensor(name, "bias")));
+
+            let mut x = conv(positions, "input.conv", "input.bn").relu();
+            for i in 0..self.residual.len() {
+                let block = format!("residual.{}", i);
+                let y = conv(&x, &format!("{}.conv1", block), &format!("{}.bn1", block)).relu();
+                let y = conv(&y, &format!("{}.conv2", block), &format!("{}.bn2", block));
+                x = (y + &x).relu();
+            }
+
+            let policy = linear(&conv(&x, "policy.conv", "policy.bn").relu().flatten(1, -1), "policy.fc");
+            let illegal = masks.flatten(1, -1).eq(0i64);
+            let policy = policy.masked_fill(&illegal, f64::NEG_INFINITY).softmax(1, tch::Kind::Float);
+            let value = linear(&conv(&x, "value.conv", "value.bn").relu().flatten(1, -1), "value.fc1").relu();
+            let value = linear(&value, "value.fc2").tanh();
+            IValue::Tuple(vec![IValue::Tensor(value), IValue::Tensor(policy)])
+        }
+    }
+
+    fn formats() -> Vec<NetworkFormat> {
+        let mut formats = vec![];
+        for input in [InputEncoding::Classic, InputEncoding::Lc0] {
+            for policy in [PolicyLayout::Classic, PolicyLayout::AlphaZero] {
+                formats.push(NetworkFormat { input, policy });
+            }
+        }
+        formats
+    }
+
+    fn positions() -> Vec<History> {
+        let mut game = History::new(Board::default(), 0);
+        let mut positions = vec![game.clone()];
+        for mov in ["e2e4", "c7c5", "g1f3", "d7d6", "d2d4", "c5d4"] {
+            game.make_move(ChessMove::from_str(mov).unwrap());
+            positions.push(game.clone());
+        }
+        positions.push(History::from_fen("8/P6k/8/8/8/8/5K2/8 w - - 12 60").unwrap());
+        positions
+    }
+
+    fn assert_close(expected: &[Evaluation], actual: &[Evaluation]) {
+        assert_eq!(expected.len(), actual.len());
+        for ((expected_priors, expected_value), (priors, value)) in expected.iter().zip(actual) {
+            assert!((expected_value - value).abs() < 1e-4, "value {} instead of {}", value, expected_value);
+            assert_eq!(expected_priors.len(), priors.len());
+            for ((expected_move, expected_prior), (mov, prior)) in expected_priors.iter().zip(priors) {
+                assert_eq!(expected_move, mov);
+                assert!(
+                    (expected_prior - prior).abs() < 1e-4,
+                    "prior of {} is {} instead of {}",
+                    mov,
+                    prior,
+                    expected_prior
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn matches_reference() {
+        for wdl in [false, true] {
+            let reference = Reference::new(wdl);
+            let weights = Weights::parse(&reference.encode()).unwrap();
+            let positions = positions();
+            assert_close(&reference.evaluate(&positions), &Network::new(&weights).unwrap().evaluate(&positions));
+        }
+    }
+
+    #[cfg(feature = "torch")]
+    #[test]
+    fn matches_libtorch() {
+        for wdl in [false, true] {
+            let weights = Weights::parse(&Reference::new(wdl).encode()).unwrap();
+            let positions = positions();
+            let expected = crate::lc0::Network::new(&weights).unwrap().evaluate(&positions);
+            assert_close(&expected, &Network::new(&weights).unwrap().evaluate(&positions));
+        }
+    }
+
+    #[test]
+    fn exported_matches_reference() {
+        for format in formats() {
+            let reference = ExportedReference::new(format);
+            let weights = export::Weights::parse(&encode(&reference.tensors())).unwrap();
+            assert_eq!(weights.format, format);
+            let positions = positions();
+            assert_close(&reference.evaluate(&positions), &Network::exported(&weights).unwrap().evaluate(&positions));
+        }
+    }
+
+    /// The CPU backend against the libtorch path of `get_neural_output_batched`,
+    /// with the network's tch ops standing in for a TorchScript module.
+    #[cfg(feature = "torch")]
+    #[test]
+    fn exported_matches_libtorch() {
+        for format in formats() {
+            let reference = ExportedReference::new(format);
+            let weights = export::Weights::parse(&encode(&reference.tensors())).unwrap();
+            let positions = positions();
+            let expected = tch::no_grad(|| {
+                get_neural_output_batched_with(&positions, format, |inputs| Ok(reference.torch_forward(inputs)))
+            });
+            assert_close(&expected, &Network::exported(&weights).unwrap().evaluate(&positions));
+        }
+    }
+}
@@ -1,5 +1,10 @@
+use crate::history::History;
+
 use chess::*;
 
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
 type EncodedPositions = ndarray::ArrayBase<ndarray::OwnedRepr<f32>, ndarray::Dim<[usize; 4]>>;
 
 fn coords(mut sq: Square, flip: bool) -> (usize, usize) {
@@ -199,6 +204,116 @@ pub fn encode_positions(boards: &[Board]) -> EncodedPositions {
     planes
 }
 
+/// Positions the Lc0 encoding looks at: the current one and seven before it.
+const LC0_HISTORY: usize = 8;
+/// Planes per position in the Lc0 encoding: our six piece types, theirs and a repetition flag.
+const LC0_POSITION_PLANES: usize = 13;
+const LC0_PLANES: usize = LC0_HISTORY * LC0_POSITION_PLANES + 8;
+
+/// Lc0's classical 112-plane encoding. Every one of the last eight positions
+/// gets 13 planes, seen from the side to move now: our pieces, their pieces,
+/// and whether the position is a repetition. Positions before the start of the
+/// history are left empty; en passant shows through the previous position.
+/// After them come our queenside and kingside castling rights, theirs, black
+/// to move, the halfmove clock as a plain count, which is what Lc0 feeds
+/// networks of this input format, an empty plane and a plane of ones.
+pub fn encode_lc0_positions(histories: &[History]) -> EncodedPositions {
+    let count = histories.len();
+    let mut planes = ndarray::Array::<f32, _>::zeros((count, LC0_PLANES, 8, 8));
+    let pieces = [
+        Piece::Pawn,
+        Piece::Knight,
+        Piece::Bishop,
+        Piece::Rook,
+        Piece::Queen,
+        Piece::King,
+    ];
+
+    for (i, history) in histories.iter().enumerate() {
+        let board = history.board();
+        let us = board.side_to_move();
+        let flip = us == Color::Black;
+
+        for (j, (position, repeated)) in history.positions().take(LC0_HISTORY).enumerate() {
+            let base = j * LC0_POSITION_PLANES;
+            for (k, piece) in pieces.iter().enumerate() {
+                for (plane, color) in [(base + k, us), (base + 6 + k, !us)] {
+                    for sq in position.pieces(*piece) & position.color_combined(color) {
+                        let (r, f) = coords(sq, flip);
+                        planes[[i, plane, r, f]] = 1.0;
+                    }
+                }
+            }
+
+            if repeated {
+                planes.slice_mut(ndarray::s![i, base + 12, .., ..]).fill(1.0);
+            }
+        }
+
+        let aux = LC0_HISTORY * LC0_POSITION_PLANES;
+        let flags = [
+            board.castle_rights(us).has_queenside(),
+            board.castle_rights(us).has_kingside(),
+            board.castle_rights(!us).has_queenside(),
+            board.castle_rights(!us).has_kingside(),
+            flip,
+        ];
+        for (plane, flag) in flags.into_iter().enumerate() {
+            if flag {
+                planes.slice_mut(ndarray::s![i, aux + plane, .., ..]).fill(1.0);
+            }
+        }
+        planes.slice_mut(ndarray::s![i, aux + 5, .., ..]).fill(history.rule50() as f32);
+        planes.slice_mut(ndarray::s![i, aux + 7, .., ..]).fill(1.0);
+    }
+
+    planes
+}
+
+/// What a network takes as input.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputEncoding {
+    /// The 16 planes of `encode_positions`: the current position's pieces and castling rights.
+    Classic,
+    /// The 112 planes of `encode_lc0_positions`, with the game's history.
+    Lc0,
+}
+
+impl InputEncoding {
+    pub fn planes(self) -> usize {
+        match self {
+            InputEncoding::Classic => 16,
+            InputEncoding::Lc0 => LC0_PLANES,
+        }
+    }
+
+    pub fn encode(self, histories: &[History]) -> EncodedPositions {
+        match self {
+            InputEncoding::Classic => {
+                encode_positions(&histories.iter().map(History::board).collect::<Vec<_>>())
+            }
+            InputEncoding::Lc0 => encode_lc0_positions(histories),
+        }
+    }
+
+    /// A key for caching the network's output for `history`, covering
+    /// everything the encoding looks at.
+    pub fn cache_key(self, history: &History) -> u64 {
+        match self {
+            InputEncoding::Classic => history.board().get_hash(),
+            InputEncoding::Lc0 => {
+                let mut hasher = DefaultHasher::new();
+                for (position, repeated) in history.positions().take(LC0_HISTORY) {
+                    position.get_hash().hash(&mut hasher);
+                    repeated.hash(&mut hasher);
+                }
+                history.rule50().hash(&mut hasher);
+                hasher.finish()
+            }
+        }
+    }
+}
+
 #[allow(unused_assignments)]
 pub fn move_to_idx(mut mov: ChessMove, flip: bool) -> (isize, isize, isize) {
     if flip {
@@ -267,126 +382,306 @@ pub fn move_to_idx(mut mov: ChessMove, flip: bool) -> (isize, isize, isize) {
     (direction_and_distance_plane, from_rank, from_file)
 }
 
+/// The share of the queen promotion's prior an underpromotion gets when the
+/// policy layout has no entry for it. Small, as underpromotions rarely matter,
+/// but not zero, or the search would never look at one that mates.
+const UNDERPROMOTION_SHARE: f32 = 0.1;
+
+/// How a network's policy head lays out the moves: planes of 8x8 from-squares,
+/// seen from the side to move.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PolicyLayout {
+    /// The 72 planes of `move_to_idx`. Every promotion shares the plane of the
+    /// pawn move, so underpromotions get a share of the queen promotion's prior.
+    Classic,
+    /// AlphaZero's 73 planes: 56 queen move planes, direction by direction in
+    /// `move_to_idx` order with distances 1 to 7, the 8 knight planes, and 9
+    /// underpromotion planes, knight, bishop then rook, each for the capture
+    /// towards the a-file, the push and the capture towards the h-file.
+    AlphaZero,
+}
+
+impl PolicyLayout {
+    pub fn planes(self) -> usize {
+        match self {
+            PolicyLayout::Classic => 72,
+            PolicyLayout::AlphaZero => 73,
+        }
+    }
+
+    /// The index of `mov` in the flattened policy, or `None` if the layout
+    /// has no entry of its own for it.
+    pub fn index(self, mov: ChessMove, flip: bool) -> Option<usize> {
+        let (plane, rank, file) = move_to_idx(mov, flip);
+        let underpromotion = mov.get_promotion().filter(|piece| *piece != Piece::Queen);
+        let plane = match (self, underpromotion) {
+            (PolicyLayout::Classic, Some(_)) => return None,
+            (PolicyLayout::Classic, None) => plane,
+            (PolicyLayout::AlphaZero, Some(piece)) => {
+                let piece = match piece {
+                    Piece::Knight => 0,
+                    Piece::Bishop => 1,
+                    _ => 2,
+                };
+                // flipping the board keeps the files, so this needs no flip
+                let direction = icoords(mov.get_dest()).1 - icoords(mov.get_source()).1 + 1;
+                64 + piece * 3 + direction
+            }
+            (PolicyLayout::AlphaZero, None) if plane >= 64 => plane - 8,
+            (PolicyLayout::AlphaZero, None) => plane / 8 * 7 + plane % 8 - 1,
+        };
+
+        Some((plane * 64 + rank * 8 + file) as usize)
+    }
+
+    /// The entry the prior of `mov` is read from and the share of it `mov` gets:
+    /// all of its own entry, or `UNDERPROMOTION_SHARE` of the queen promotion's.
+    pub fn prior_index(self, mov: ChessMove, flip: bool) -> (usize, f32) {
+        match self.index(mov, flip) {
+            Some(index) => (index, 1.0),
+            None => {
+                let queen = ChessMove::new(mov.get_source(), mov.get_dest(), Some(Piece::Queen));
+                (self.index(queen, flip).unwrap(), UNDERPROMOTION_SHARE)
+            }
+        }
+    }
+}
+
 type MoveMasks = ndarray::ArrayBase<ndarray::OwnedRepr<i32>, ndarray::Dim<[usize; 4]>>;
 
-pub fn legal_move_masks(boards: &[Board]) -> MoveMasks {
+pub fn legal_move_masks(boards: &[Board], layout: PolicyLayout) -> MoveMasks {
     let count = boards.len();
-    let mut masks = ndarray::Array::<i32, _>::zeros((count, 72, 8, 8));
+    let mut masks = ndarray::Array::<i32, _>::zeros((count, layout.planes(), 8, 8));
 
     for (i, board) in boards.iter().enumerate() {
         let flip = board.side_to_move() == Color::Black;
 
-        let movegen = MoveGen::new_legal(&board);
+        let movegen = MoveGen::new_legal(board);
         for mov in movegen {
-            let (plane_idx, rank_idx, file_idx) = move_to_idx(mov, flip);
-            masks[[i, plane_idx as usize, rank_idx as usize, file_idx as usize]] = 1;
+            if let Some(idx) = layout.index(mov, flip) {
+                masks[[i, idx / 64, idx / 8 % 8, idx % 8]] = 1;
+            }
         }
     }
 
     masks
 }
 
+/// The input encoding and policy layout a network was trained with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NetworkFormat {
+    pub input: InputEncoding,
+    pub policy: PolicyLayout,
+}
+
 /// Get the policy head probabilities and the value head prediction for a given position.
-pub fn get_neural_output(board: Board, network: &tch::CModule) -> (Vec<(ChessMove, f32)>, f32) {
-    let position = encode_positions(&[board]);
-    let mask = legal_move_masks(&[board]);
+#[cfg(feature = "torch")]
+pub fn get_neural_output(
+    history: &History,
+    network: &tch::CModule,
+    format: NetworkFormat,
+) -> (Vec<(ChessMove, f32)>, f32) {
+    get_neural_output_batched(std::slice::from_ref(history), network, format).remove(0)
+}
+
+/// The values and the flattened policies of a batch, a row per position.
+#[cfg(any(feature = "torch", feature = "onnx"))]
+pub type Outputs = (ndarray::ArrayD<f32>, ndarray::ArrayD<f32>);
+
+/// Check that the policy the network gave has an entry for every move of
+/// `format`, which it won't if the network was trained with another layout.
+#[cfg(any(feature = "torch", feature = "onnx"))]
+pub fn check_outputs(outputs: Outputs, format: NetworkFormat) -> Result<Outputs, String> {
+    let entries = outputs.1.shape()[1];
+    if entries != format.policy.planes() * 64 {
+        return Err(format!("policy has {} entries instead of {}", entries, format.policy.planes() * 64));
+    }
+
+    Ok(outputs)
+}
 
-    let position: tch::Tensor = tch::Tensor::try_from(position).unwrap();
-    let mask: tch::Tensor = tch::Tensor::try_from(mask).unwrap();
+/// Run the network on a batch, returning the value and the flattened policy of
+/// each position. Fails if the network does not take inputs shaped for `format`.
+#[cfg(feature = "torch")]
+fn forward(histories: &[History], network: &tch::CModule, format: NetworkFormat) -> Result<Outputs, String> {
+    run(histories, format, |inputs| network.forward_is(inputs).map_err(|err| err.to_string()))
+}
+
+/// `forward` with `network` standing in for the module: it gets the encoded
+/// positions and legal move masks and returns what the module would.
+#[cfg(feature = "torch")]
+fn run(
+    histories: &[History],
+    format: NetworkFormat,
+    network: impl FnOnce(&[tch::jit::IValue]) -> Result<tch::jit::IValue, String>,
+) -> Result<Outputs, String> {
+    let boards = histories.iter().map(History::board).collect::<Vec<_>>();
+    let positions = format.input.encode(histories);
+    let masks = legal_move_masks(&boards, format.policy);
+
+    let positions: tch::Tensor = tch::Tensor::try_from(positions).unwrap();
+    let masks: tch::Tensor = tch::Tensor::try_from(masks).unwrap();
 
-    let output = network
-        .forward_is(&[
-            tch::jit::IValue::Tensor(position),
-            tch::jit::IValue::Tensor(mask),
-        ])
-        .unwrap();
+    let output = network(&[
+        tch::jit::IValue::Tensor(positions),
+        tch::jit::IValue::Tensor(masks),
+    ])?;
 
     match output {
-        tch::jit::IValue::Tuple(tensors) => {
+        tch::jit::IValue::Tuple(tensors) if tensors.len() == 2 => {
             let value = match &tensors[0] {
                 tch::jit::IValue::Tensor(tensor) => tensor,
-                _ => unreachable!(),
+                _ => return Err("value is not a tensor".to_string()),
             };
 
             let policy = match &tensors[1] {
                 tch::jit::IValue::Tensor(tensor) => tensor,
-                _ => unreachable!(),
+                _ => return Err("policy is not a tensor".to_string()),
             };
 
-            let policy = &policy.nan_to_num(0.0, 0.0, 0.0);
+            let shape = [histories.len() as i64, -1];
+            let value = value.f_reshape(&shape).map_err(|err| err.to_string())?;
+            let policy = policy
+                .nan_to_num(0.0, 0.0, 0.0)
+                .f_reshape(&shape)
+                .map_err(|err| err.to_string())?;
+
+            let value: ndarray::ArrayD<f32> = (&value).try_into().map_err(|err| format!("{}", err))?;
+            let policy: ndarray::ArrayD<f32> = (&policy).try_into().map_err(|err| format!("{}", err))?;
+            check_outputs((value, policy), format)
+        }
+        _ => Err("network does not return a (value, policy) tuple".to_string()),
+    }
+}
 
-            let value: ndarray::ArrayBase<ndarray::OwnedRepr<f32>, _> = value.try_into().unwrap();
-            let value = value[[0, 0]];
+/// Find the format a network was trained with, by trying each on a position
+/// with `forward`, which runs the network on a batch in the given format.
+#[cfg(any(feature = "torch", feature = "onnx"))]
+pub fn probe_format(
+    mut forward: impl FnMut(&[History], NetworkFormat) -> Result<Outputs, String>,
+) -> Result<NetworkFormat, String> {
+    let history = History::new(Board::default(), 0);
+    let mut errors = vec![];
+    for input in [InputEncoding::Classic, InputEncoding::Lc0] {
+        for policy in [PolicyLayout::Classic, PolicyLayout::AlphaZero] {
+            let format = NetworkFormat { input, policy };
+            match forward(std::slice::from_ref(&history), format) {
+                Ok(_) => return Ok(format),
+                Err(err) => errors.push(format!("{:?} input, {:?} policy: {}", input, policy, err)),
+            }
+        }
+    }
 
-            let policy: ndarray::ArrayBase<ndarray::OwnedRepr<f32>, _> = policy.try_into().unwrap();
-            let flat_policy: Vec<f32> = policy.into_iter().collect::<Vec<_>>();
+    Err(errors.join("; "))
+}
 
-            let mut move_probabilities = Vec::new();
-            let movegen = MoveGen::new_legal(&board);
-            for mov in movegen {
-                let flip = board.side_to_move() == Color::Black;
+/// Find the format `network` was trained with, by trying each on a position.
+#[cfg(feature = "torch")]
+pub fn detect_format(network: &tch::CModule) -> Result<NetworkFormat, String> {
+    probe_format(|histories, format| tch::no_grad(|| forward(histories, network, format)))
+}
 
-                let (plane_idx, rank_idx, file_idx) = move_to_idx(mov, flip);
-                let mov_idx = plane_idx * 64 + rank_idx * 8 + file_idx;
-                move_probabilities.push((mov, flat_policy[mov_idx as usize]));
-            }
+/// The priors of the legal moves and the value of each position, from the
+/// network's outputs for the batch.
+#[cfg(any(feature = "torch", feature = "onnx"))]
+pub fn outputs_to_evaluations(
+    histories: &[History],
+    (value, policy): &Outputs,
+    format: NetworkFormat,
+) -> Vec<(Vec<(ChessMove, f32)>, f32)> {
+    let mut outputs = vec![];
 
-            (move_probabilities, value)
+    for (i, board) in histories.iter().map(History::board).enumerate() {
+        let flip = board.side_to_move() == Color::Black;
+        let mut move_probabilities = Vec::new();
+        let movegen = MoveGen::new_legal(&board);
+        for mov in movegen {
+            let (idx, share) = format.policy.prior_index(mov, flip);
+            let probability = policy[[i, idx]] * share;
+            move_probabilities.push((mov, probability));
         }
-        _ => unreachable!(),
+
+        outputs.push((move_probabilities, value[[i, 0]]));
     }
+
+    outputs
 }
 
 /// Get the policy head probabilities and the value head prediction for a batch of positions.
+#[cfg(feature = "torch")]
 pub fn get_neural_output_batched(
-    boards: &[Board],
+    histories: &[History],
     network: &tch::CModule,
+    format: NetworkFormat,
 ) -> Vec<(Vec<(ChessMove, f32)>, f32)> {
-    let positions = encode_positions(boards);
-    let masks = legal_move_masks(boards);
-
-    let positions: tch::Tensor = tch::Tensor::try_from(positions).unwrap();
-    let masks: tch::Tensor = tch::Tensor::try_from(masks).unwrap();
-
-    let output = network
-        .forward_is(&[
-            tch::jit::IValue::Tensor(positions),
-            tch::jit::IValue::Tensor(masks),
-        ])
-        .unwrap();
-
-    match output {
-        tch::jit::IValue::Tuple(tensors) => {
-            let value = match &tensors[0] {
-                tch::jit::IValue::Tensor(tensor) => tensor,
-                _ => unreachable!(),
-            };
+    outputs_to_evaluations(histories, &forward(histories, network, format).unwrap(), format)
+}
 
-            let policy = match &tensors[1] {
-                tch::jit::IValue::Tensor(tensor) => tensor,
-                _ => unreachable!(),
-            };
+/// `get_neural_output_batched` with `network` standing in for the module, as `run` takes it.
+#[cfg(all(test, feature = "torch", feature = "cpu"))]
+pub(crate) fn get_neural_output_batched_with(
+    histories: &[History],
+    format: NetworkFormat,
+    network: impl FnOnce(&[tch::jit::IValue]) -> Result<tch::jit::IValue, String>,
+) -> Vec<(Vec<(ChessMove, f32)>, f32)> {
+    outputs_to_evaluations(histories, &run(histories, format, network).unwrap(), format)
+}
 
-            let policy = &policy.nan_to_num(0.0, 0.0, 0.0);
-            let value: ndarray::ArrayBase<ndarray::OwnedRepr<f32>, _> = value.try_into().unwrap();
-            let policy: ndarray::ArrayBase<ndarray::OwnedRepr<f32>, _> = policy.try_into().unwrap();
-            let mut outputs = vec![];
-
-            for (i, board) in boards.iter().enumerate() {
-                let flip = board.side_to_move() == Color::Black;
-                let mut move_probabilities = Vec::new();
-                let movegen = MoveGen::new_legal(&board);
-                for mov in movegen {
-                    let (plane_idx, rank_idx, file_idx) = move_to_idx(mov, flip);
-                    let mov_idx = plane_idx * 64 + rank_idx * 8 + file_idx;
-                    move_probabilities.push((mov, policy[[i, mov_idx as usize]]));
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashSet;
+    use std::str::FromStr;
+
+    const POSITIONS: [&str; 5] = [
+        "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+        "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1",
+        "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R b KQkq - 0 1",
+        // promotions with and without captures towards both files
+        "n1n5/1P4k1/8/8/8/8/6K1/8 w - - 0 1",
+        "8/6k1/8/8/8/8/1p4K1/N1N5 b - - 0 1",
+    ];
+
+    #[test]
+    fn policy_indices_are_distinct_and_in_range() {
+        for fen in POSITIONS {
+            let board = Board::from_str(fen).unwrap();
+            let flip = board.side_to_move() == Color::Black;
+            for layout in [PolicyLayout::Classic, PolicyLayout::AlphaZero] {
+                let mut seen = HashSet::new();
+                for mov in MoveGen::new_legal(&board) {
+                    let underpromotion = mov.get_promotion().is_some_and(|piece| piece != Piece::Queen);
+                    let index = match (layout.index(mov, flip), layout) {
+                        (None, PolicyLayout::Classic) if underpromotion => continue,
+                        (None, _) => panic!("{:?} has no index for {} in {}", layout, mov, fen),
+                        (Some(index), _) => index,
+                    };
+                    assert!(index < layout.planes() * 64, "{:?} index {} of {} is out of range", layout, index, mov);
+                    assert!(seen.insert(index), "{:?} index {} of {} in {} is taken twice", layout, index, mov, fen);
                 }
-
-                outputs.push((move_probabilities, value[[i, 0]]));
             }
+        }
+    }
 
-            outputs
+    #[test]
+    fn alphazero_underpromotions_have_their_own_planes() {
+        for fen in &POSITIONS[3..] {
+            let board = Board::from_str(fen).unwrap();
+            let flip = board.side_to_move() == Color::Black;
+            let planes = MoveGen::new_legal(&board)
+                .filter(|mov| mov.get_promotion().is_some_and(|piece| piece != Piece::Queen))
+                .map(|mov| PolicyLayout::AlphaZero.index(mov, flip).unwrap() / 64)
+                .collect::<HashSet<_>>();
+            assert_eq!(planes, (64..73).collect());
         }
-        _ => unreachable!(),
+    }
+
+    #[test]
+    fn lc0_rule50_plane_is_the_halfmove_count() {
+        let history = History::from_fen("8/6k1/8/8/8/8/1p4K1/8 w - - 37 60").unwrap();
+        let planes = encode_lc0_positions(&[history]);
+        let aux = LC0_HISTORY * LC0_POSITION_PLANES;
+        assert!(planes.slice(ndarray::s![0, aux + 5, .., ..]).iter().all(|&value| value == 37.0));
     }
 }
@@ -0,0 +1,406 @@
+use crate::cache::Evaluation;
+use crate::config::EXTERNAL_TIMEOUT;
+#[cfg(feature = "cpu")]
+use crate::cpu;
+#[cfg(feature = "torch")]
+use crate::encoding::{detect_format, get_neural_output_batched};
+#[cfg(any(feature = "torch", feature = "onnx", feature = "cpu"))]
+use crate::encoding::NetworkFormat;
+#[cfg(feature = "onnx")]
+use crate::encoding::outputs_to_evaluations;
+#[cfg(any(feature = "torch", feature = "cpu"))]
+use crate::encoding::InputEncoding;
+#[cfg(feature = "cpu")]
+use crate::export;
+use crate::history::History;
+use crate::lc0;
+#[cfg(feature = "onnx")]
+use crate::onnx;
+use crate::uci_engine::{Limits, UciEngine};
+
+use chess::*;
+
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Scores positions for the search: a prior for every legal move and a value
+/// in [-1, 1] for the side to move. Shared by all search threads.
+pub trait Evaluator: Send + Sync {
+    /// Evaluate a batch of positions, none of which may be game over.
+    fn evaluate(&self, positions: &[History]) -> Vec<Evaluation>;
+
+    /// The key to cache the evaluation of `position` under. Evaluators that
+    /// look at more than the current position must include that in the key.
+    fn cache_key(&self, position: &History) -> u64 {
+        position.board().get_hash()
+    }
+}
+
+/// A TorchScript network taking the encoded positions and legal move masks.
+#[cfg(feature = "torch")]
+pub struct TorchEvaluator {
+    model: tch::CModule,
+    format: NetworkFormat,
+}
+
+#[cfg(feature = "torch")]
+impl TorchEvaluator {
+    pub fn load(path: &str) -> Result<Self, String> {
+        if !Path::new(path).is_file() {
+            return Err(format!("network file '{}' not found", path));
+        }
+
+        let mut model = tch::CModule::load(path)
+            .map_err(|err| format!("'{}' is not a valid TorchScript module: {}", path, err))?;
+        model.set_eval();
+        let format = detect_format(&model)
+            .map_err(|err| format!("'{}' does not take the inputs of any known format ({})", path, err))?;
+        Ok(Self { model, format })
+    }
+
+    pub fn format(&self) -> NetworkFormat {
+        self.format
+    }
+}
+
+#[cfg(feature = "torch")]
+impl Evaluator for TorchEvaluator {
+    fn evaluate(&self, positions: &[History]) -> Vec<Evaluation> {
+        // gradients are tracked per thread, so turn them off on whichever thread asks
+        tch::no_grad(|| get_neural_output_batched(positions, &self.model, self.format))
+    }
+
+    fn cache_key(&self, position: &History) -> u64 {
+        self.format.input.cache_key(position)
+    }
+}
+
+// Lc0 networks run on the pure Rust backend when it is built in, and with libtorch otherwise
+#[cfg(feature = "cpu")]
+type Lc0Network = cpu::Network;
+#[cfg(feature = "cpu")]
+const LC0_BACKEND: &str = "the CPU backend";
+#[cfg(all(feature = "torch", not(feature = "cpu")))]
+type Lc0Network = lc0::Network;
+#[cfg(all(feature = "torch", not(feature = "cpu")))]
+const LC0_BACKEND: &str = "libtorch";
+
+/// An Lc0 network from a protobuf weights file.
+#[cfg(any(feature = "torch", feature = "cpu"))]
+pub struct Lc0Evaluator {
+    network: Lc0Network,
+}
+
+#[cfg(any(feature = "torch", feature = "cpu"))]
+impl Lc0Evaluator {
+    pub fn load(path: &str) -> Result<(Self, lc0::Weights), String> {
+        if !Path::new(path).is_file() {
+            return Err(format!("network file '{}' not found", path));
+        }
+
+        let weights = lc0::Weights::load(path)?;
+        let network = Lc0Network::new(&weights)
+            .map_err(|err| format!("'{}' is not a supported Lc0 network: {}", path, err))?;
+        Ok((Self { network }, weights))
+    }
+}
+
+#[cfg(any(feature = "torch", feature = "cpu"))]
+impl Evaluator for Lc0Evaluator {
+    fn evaluate(&self, positions: &[History]) -> Vec<Evaluation> {
+        self.network.evaluate(positions)
+    }
+
+    fn cache_key(&self, position: &History) -> u64 {
+        InputEncoding::Lc0.cache_key(position)
+    }
+}
+
+/// A network of the engine's own format, exported from its TorchScript module
+/// and run on the CPU backend.
+#[cfg(feature = "cpu")]
+pub struct ExportedEvaluator {
+    network: cpu::Network,
+    format: NetworkFormat,
+}
+
+#[cfg(feature = "cpu")]
+impl ExportedEvaluator {
+    pub fn load(path: &str) -> Result<(Self, export::Weights), String> {
+        if !Path::new(path).is_file() {
+            return Err(format!("network file '{}' not found", path));
+        }
+
+        let weights = export::Weights::load(path)?;
+        let network = cpu::Network::exported(&weights)
+            .map_err(|err| format!("'{}' is not a supported exported network: {}", path, err))?;
+        Ok((Self { network, format: weights.format }, weights))
+    }
+}
+
+#[cfg(feature = "cpu")]
+impl Evaluator for ExportedEvaluator {
+    fn evaluate(&self, positions: &[History]) -> Vec<Evaluation> {
+        self.network.evaluate(positions)
+    }
+
+    fn cache_key(&self, position: &History) -> u64 {
+        self.format.input.cache_key(position)
+    }
+}
+
+/// An ONNX network taking the encoded positions and legal move masks.
+#[cfg(feature = "onnx")]
+pub struct OnnxEvaluator {
+    network: onnx::Network,
+}
+
+#[cfg(feature = "onnx")]
+impl OnnxEvaluator {
+    pub fn load(path: &str) -> Result<Self, String> {
+        if !Path::new(path).is_file() {
+            return Err(format!("network file '{}' not found", path));
+        }
+
+        Ok(Self {
+            network: onnx::Network::load(path)?,
+        })
+    }
+
+    pub fn format(&self) -> NetworkFormat {
+        self.network.format()
+    }
+}
+
+#[cfg(feature = "onnx")]
+impl Evaluator for OnnxEvaluator {
+    fn evaluate(&self, positions: &[History]) -> Vec<Evaluation> {
+        outputs_to_evaluations(positions, &self.network.forward(positions).unwrap(), self.format())
+    }
+
+    fn cache_key(&self, position: &History) -> u64 {
+        self.format().input.cache_key(position)
+    }
+}
+
+/// Load the network at `path`: an Lc0 weights file if it is named `.pb` or
+/// `.pb.gz`, an ONNX model if it is named `.onnx`, an exported network if it
+/// is named `.dnw`, and a TorchScript module otherwise. Also describes what was loaded.
+pub fn load_network(path: &str) -> Result<(Arc<dyn Evaluator>, String), String> {
+    if is_onnx_file(path) {
+        load_onnx_network(path)
+    } else if is_exported_file(path) {
+        load_exported_network(path)
+    } else if lc0::is_weights_file(path) {
+        load_lc0_network(path)
+    } else {
+        load_torch_network(path)
+    }
+}
+
+/// Whether `path` names an ONNX model rather than a TorchScript module.
+fn is_onnx_file(path: &str) -> bool {
+    Path::new(path).file_name().and_then(|name| name.to_str()).unwrap_or("").ends_with(".onnx")
+}
+
+/// Whether `path` names a network exported for the CPU backend.
+fn is_exported_file(path: &str) -> bool {
+    Path::new(path).file_name().and_then(|name| name.to_str()).unwrap_or("").ends_with(".dnw")
+}
+
+#[cfg(any(feature = "torch", feature = "cpu"))]
+fn load_lc0_network(path: &str) -> Result<(Arc<dyn Evaluator>, String), String> {
+    let (evaluator, weights) = Lc0Evaluator::load(path)?;
+    let description = format!(
+        "Lc0 {}x{}{} network with a {} value head on {}",
+        weights.blocks(),
+        weights.filters(),
+        if weights.has_se() { " SE" } else { "" },
+        if weights.wdl() { "WDL" } else { "classical" },
+        LC0_BACKEND
+    );
+    Ok((Arc::new(evaluator), description))
+}
+
+#[cfg(not(any(feature = "torch", feature = "cpu")))]
+fn load_lc0_network(path: &str) -> Result<(Arc<dyn Evaluator>, String), String> {
+    Err(format!("'{}' is an Lc0 network, which needs a build with the cpu or torch feature", path))
+}
+
+#[cfg(feature = "cpu")]
+fn load_exported_network(path: &str) -> Result<(Arc<dyn Evaluator>, String), String> {
+    let (evaluator, weights) = ExportedEvaluator::load(path)?;
+    let description = format!(
+        "exported {}x{} network with {:?} input and {:?} policy layout on the CPU backend",
+        weights.network.blocks(),
+        weights.network.filters(),
+        weights.format.input,
+        weights.format.policy
+    );
+    Ok((Arc::new(evaluator), description))
+}
+
+#[cfg(not(feature = "cpu"))]
+fn load_exported_network(path: &str) -> Result<(Arc<dyn Evaluator>, String), String> {
+    Err(format!("'{}' is an exported network, which needs a build with the cpu feature", path))
+}
+
+#[cfg(feature = "onnx")]
+fn load_onnx_network(path: &str) -> Result<(Arc<dyn Evaluator>, String), String> {
+    let evaluator = OnnxEvaluator::load(path)?;
+    let format = evaluator.format();
+    let description = format!("ONNX network with {:?} input and {:?} policy layout", format.input, format.policy);
+    Ok((Arc::new(evaluator), description))
+}
+
+#[cfg(not(feature = "onnx"))]
+fn load_onnx_network(path: &str) -> Result<(Arc<dyn Evaluator>, String), String> {
+    Err(format!("'{}' is an ONNX model, which needs a build with the onnx feature", path))
+}
+
+#[cfg(feature = "torch")]
+fn load_torch_network(path: &str) -> Result<(Arc<dyn Evaluator>, String), String> {
+    let evaluator = TorchEvaluator::load(path)?;
+    let format = evaluator.format();
+    let description = format!("{:?} input and {:?} policy layout", format.input, format.policy);
+    Ok((Arc::new(evaluator), description))
+}
+
+#[cfg(not(feature = "torch"))]
+fn load_torch_network(path: &str) -> Result<(Arc<dyn Evaluator>, String), String> {
+    Err(format!(
+        "'{}' is taken for a TorchScript module, which needs a build with the torch feature",
+        path
+    ))
+}
+
+/// A deterministic stand-in for a network: uniform priors and a value from the
+/// material balance. Lets the search run and be measured without a model file.
+pub struct MockEvaluator;
+
+fn material(board: &Board, color: Color) -> f32 {
+    [
+        (Piece::Pawn, 1.0),
+        (Piece::Knight, 3.0),
+        (Piece::Bishop, 3.0),
+        (Piece::Rook, 5.0),
+        (Piece::Queen, 9.0),
+    ]
+    .iter()
+    .map(|(piece, value)| (board.pieces(*piece) & board.color_combined(color)).popcnt() as f32 * value)
+    .sum()
+}
+
+impl Evaluator for MockEvaluator {
+    fn evaluate(&self, positions: &[History]) -> Vec<Evaluation> {
+        positions
+            .iter()
+            .map(|position| {
+                let board = &position.board();
+                let moves = MoveGen::new_legal(board).collect::<Vec<_>>();
+                let prior = 1.0 / moves.len().max(1) as f32;
+                let side = board.side_to_move();
+                let balance = material(board, side) - material(board, !side);
+
+                (
+                    moves.into_iter().map(|mov| (mov, prior)).collect(),
+                    (balance / 10.0).tanh(),
+                )
+            })
+            .collect()
+    }
+}
+
+/// How the external engine's value is mixed into the network's.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Blend {
+    /// Share of the external value in the blended value, from 0 to 1.
+    pub weight: f32,
+    /// Share of positions sent to the external engine at all, from 0 to 1. The
+    /// choice is made by position hash so that a position always gets the same value.
+    pub fraction: f32,
+}
+
+impl Blend {
+    fn selects(&self, board: &Board) -> bool {
+        ((board.get_hash() % 10000) as f32) < self.fraction * 10000.0
+    }
+}
+
+/// Takes the priors from another evaluator and blends its value with a short
+/// search of an external UCI engine. Each search thread talks to its own
+/// engine process; the processes live as long as the evaluator.
+pub struct ExternalEvaluator {
+    command: String,
+    limits: Limits,
+    blend: Blend,
+    priors: Arc<dyn Evaluator>,
+    // engines not in use by a search thread right now
+    engines: Mutex<Vec<UciEngine>>,
+    // only the first failure is reported, the engine is likely to keep failing
+    failed: AtomicBool,
+}
+
+impl ExternalEvaluator {
+    pub fn new(command: &str, limits: Limits, blend: Blend, priors: Arc<dyn Evaluator>) -> Self {
+        Self {
+            command: command.to_string(),
+            limits,
+            blend,
+            priors,
+            engines: Mutex::new(vec![]),
+            failed: AtomicBool::new(false),
+        }
+    }
+
+    fn report(&self, err: &str) {
+        if !self.failed.swap(true, Ordering::Relaxed) {
+            println!("info string external engine: {}, using the network value", err);
+        }
+    }
+}
+
+impl Evaluator for ExternalEvaluator {
+    fn evaluate(&self, positions: &[History]) -> Vec<Evaluation> {
+        let mut evaluations = self.priors.evaluate(positions);
+        if !positions.iter().any(|position| self.blend.selects(&position.board())) {
+            return evaluations;
+        }
+
+        let engine = self.engines.lock().unwrap().pop();
+        let mut engine = match engine {
+            Some(engine) => engine,
+            None => match UciEngine::start(&self.command, Duration::from_millis(EXTERNAL_TIMEOUT)) {
+                Ok(engine) => engine,
+                Err(err) => {
+                    self.report(&err);
+                    return evaluations;
+                }
+            },
+        };
+
+        for (evaluation, position) in evaluations.iter_mut().zip(positions) {
+            let board = position.board();
+            if !self.blend.selects(&board) {
+                continue;
+            }
+
+            match engine.analyse(&board, self.limits) {
+                Ok(analysis) => {
+                    let weight = self.blend.weight;
+                    evaluation.1 = (1.0 - weight) * evaluation.1 + weight * analysis.score.value();
+                }
+                Err(err) => self.report(&err),
+            }
+        }
+        self.engines.lock().unwrap().push(engine);
+
+        evaluations
+    }
+
+    fn cache_key(&self, position: &History) -> u64 {
+        self.priors.cache_key(position)
+    }
+}
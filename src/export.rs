@@ -0,0 +1,380 @@
+use crate::cache::Evaluation;
+use crate::encoding::{InputEncoding, NetworkFormat, PolicyLayout};
+use crate::history::History;
+use crate::lc0::{self, ConvWeights, ResidualWeights};
+
+use chess::*;
+
+use std::collections::HashMap;
+
+// the first bytes of every exported weights file, followed by the format version
+const MAGIC: &[u8; 4] = b"DNNW";
+const VERSION: u32 = 1;
+
+/// One tensor of a weights file: its shape and its values in row-major order.
+struct Tensor {
+    shape: Vec<usize>,
+    values: Vec<f32>,
+}
+
+fn read_u32(data: &mut &[u8]) -> Result<u32, String> {
+    if data.len() < 4 {
+        return Err("truncated file".to_string());
+    }
+
+    let (bytes, rest) = data.split_at(4);
+    *data = rest;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_tensor(data: &mut &[u8]) -> Result<(String, Tensor), String> {
+    let len = read_u32(data)? as usize;
+    if data.len() < len {
+        return Err("truncated file".to_string());
+    }
+    let (name, rest) = data.split_at(len);
+    *data = rest;
+    let name = String::from_utf8(name.to_vec()).map_err(|_| "tensor name is not UTF-8".to_string())?;
+
+    let rank = read_u32(data)?;
+    let shape = (0..rank).map(|_| read_u32(data).map(|dim| dim as usize)).collect::<Result<Vec<_>, _>>()?;
+    let count = shape.iter().product::<usize>();
+    if data.len() / 4 < count {
+        return Err(format!("tensor {} is truncated", name));
+    }
+    let (values, rest) = data.split_at(count * 4);
+    *data = rest;
+    let values = values
+        .chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+        .collect();
+
+    Ok((name, Tensor { shape, values }))
+}
+
+/// The tensors of a weights file by name, taken out as the network is put together.
+struct Tensors(HashMap<String, Tensor>);
+
+impl Tensors {
+    fn contains(&self, name: &str) -> bool {
+        self.0.contains_key(name)
+    }
+
+    /// The values of `name`, or `None` if the file leaves it out. Fails if its shape is not `shape`.
+    fn optional(&mut self, name: &str, shape: &[usize]) -> Result<Option<Vec<f32>>, String> {
+        match self.0.remove(name) {
+            Some(tensor) if tensor.shape != shape => {
+                Err(format!("{} has shape {:?} instead of {:?}", name, tensor.shape, shape))
+            }
+            Some(tensor) => Ok(Some(tensor.values)),
+            None => Ok(None),
+        }
+    }
+
+    /// The values and the shape of `name`, which must have `rank` dimensions.
+    fn required(&mut self, name: &str, rank: usize) -> Result<(Vec<f32>, Vec<usize>), String> {
+        let tensor = self.0.remove(name).ok_or_else(|| format!("missing {}", name))?;
+        if tensor.shape.len() != rank {
+            return Err(format!("{} has {} dimensions instead of {}", name, tensor.shape.len(), rank));
+        }
+
+        Ok((tensor.values, tensor.shape))
+    }
+
+    /// The convolution `conv` followed by the batch norm `bn`, with the batch
+    /// norm folded in. Also returns the convolution's inputs.
+    fn conv(&mut self, conv: &str, bn: &str) -> Result<(ConvWeights, usize), String> {
+        let (weights, shape) = self.required(&format!("{}.weight", conv), 4)?;
+        let (outputs, inputs, size) = (shape[0], shape[1], shape[2]);
+        if size != shape[3] || size % 2 == 0 {
+            return Err(format!("{} has a kernel of {}x{}, which is not square and odd", conv, size, shape[3]));
+        }
+
+        let mut vector = |name: String| self.optional(&name, &[outputs]).map(Option::unwrap_or_default);
+        let biases = match vector(format!("{}.bias", conv))? {
+            biases if biases.is_empty() => vec![0.0; outputs],
+            biases => biases,
+        };
+        let conv = ConvWeights::fold(
+            weights,
+            biases,
+            vector(format!("{}.running_mean", bn))?,
+            vector(format!("{}.running_var", bn))?,
+            vector(format!("{}.weight", bn))?,
+            vector(format!("{}.bias", bn))?,
+        )
+        .map_err(|err| format!("{} {}", conv, err))?;
+
+        Ok((conv, inputs))
+    }
+
+    /// The weights and biases of the fully connected layer `name`, and its outputs.
+    fn linear(&mut self, name: &str) -> Result<(Vec<f32>, Vec<f32>, usize), String> {
+        let (weights, shape) = self.required(&format!("{}.weight", name), 2)?;
+        let biases = self.optional(&format!("{}.bias", name), &shape[..1])?;
+        Ok((weights, biases.unwrap_or_else(|| vec![0.0; shape[0]]), shape[0]))
+    }
+}
+
+/// A network of the engine's own format, exported from its TorchScript module
+/// for the CPU backend. The file starts with `DNNW` and the version as a
+/// little-endian `u32`, followed by the tensors, each as its name's length
+/// and UTF-8 bytes, its rank, its dimensions and its `f32` values, all
+/// little-endian. The tensors carry the names of a PyTorch `state_dict`:
+///
+/// - `input.conv` and `input.bn`: the first convolution, whose inputs are the
+///   16 planes of `encode_positions` or the 112 of `encode_lc0_positions`
+/// - `residual.{i}.conv1`, `residual.{i}.bn1`, `residual.{i}.conv2` and
+///   `residual.{i}.bn2` for each residual block, numbered from 0
+/// - `policy.conv`, `policy.bn` and `policy.fc`: the policy head, whose 72 or 73
+///   planes of 64 logits are laid out as `PolicyLayout` describes
+/// - `value.conv`, `value.bn`, `value.fc1` and `value.fc2`: the value head, with
+///   a single output that goes through a tanh
+///
+/// Convolutions and fully connected layers have a `weight` and may have a
+/// `bias`, batch norms may have `running_mean` and `running_var`, and with
+/// them `weight` and `bias`. Every convolution and batch norm is followed by a
+/// ReLU, as is `value.fc1`. The priors are a softmax of the policy logits over
+/// the legal moves, as the TorchScript module takes it over `legal_move_masks`.
+/// A policy temperature is folded into the weights and biases of `policy.fc`.
+pub struct Weights {
+    pub format: NetworkFormat,
+    pub network: lc0::Weights,
+}
+
+impl Weights {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let data = std::fs::read(path).map_err(|err| format!("cannot read '{}': {}", path, err))?;
+        Self::parse(&data).map_err(|err| format!("'{}' is not a supported exported network: {}", path, err))
+    }
+
+    pub fn parse(mut data: &[u8]) -> Result<Self, String> {
+        if !data.starts_with(MAGIC) {
+            return Err("wrong magic number".to_string());
+        }
+        data = &data[MAGIC.len()..];
+        let version = read_u32(&mut data)?;
+        if version != VERSION {
+            return Err(format!("unsupported version {}", version));
+        }
+
+        let mut tensors = HashMap::new();
+        while !data.is_empty() {
+            let (name, tensor) = read_tensor(&mut data)?;
+            // batch norm keeps this counter in its state dict, it plays no part in inference
+            if name.ends_with(".num_batches_tracked") {
+                continue;
+            }
+            if tensors.insert(name.clone(), tensor).is_some() {
+                return Err(format!("{} is in the file twice", name));
+            }
+        }
+        let mut tensors = Tensors(tensors);
+
+        let (input, planes) = tensors.conv("input.conv", "input.bn")?;
+        let input_encoding = [InputEncoding::Classic, InputEncoding::Lc0]
+            .into_iter()
+            .find(|encoding| encoding.planes() == planes)
+            .ok_or_else(|| format!("no input encoding has {} planes", planes))?;
+
+        let mut residual = vec![];
+        while tensors.contains(&format!("residual.{}.conv1.weight", residual.len())) {
+            let block = format!("residual.{}", residual.len());
+            residual.push(ResidualWeights {
+                conv1: tensors.conv(&format!("{}.conv1", block), &format!("{}.bn1", block))?.0,
+                conv2: tensors.conv(&format!("{}.conv2", block), &format!("{}.bn2", block))?.0,
+                se: None,
+            });
+        }
+
+        let (policy, _) = tensors.conv("policy.conv", "policy.bn")?;
+        let (ip_pol_w, ip_pol_b, outputs) = tensors.linear("policy.fc")?;
+        let policy_layout = [PolicyLayout::Classic, PolicyLayout::AlphaZero]
+            .into_iter()
+            .find(|layout| layout.planes() * 64 == outputs)
+            .ok_or_else(|| format!("no policy layout has {} entries", outputs))?;
+
+        let (value, _) = tensors.conv("value.conv", "value.bn")?;
+        let (ip1_val_w, ip1_val_b, _) = tensors.linear("value.fc1")?;
+        let (ip2_val_w, ip2_val_b, _) = tensors.linear("value.fc2")?;
+
+        if let Some(name) = tensors.0.keys().min() {
+            return Err(format!("unexpected tensor {}", name));
+        }
+
+        Ok(Self {
+            format: NetworkFormat {
+                input: input_encoding,
+                policy: policy_layout,
+            },
+            network: lc0::Weights {
+                input,
+                residual,
+                policy,
+                ip_pol_w,
+                ip_pol_b,
+                value,
+                ip1_val_w,
+                ip1_val_b,
+                ip2_val_w,
+                ip2_val_b,
+                wdl: false,
+            },
+        })
+    }
+}
+
+/// The priors and values of `positions` from the policy logits and the values
+/// an exported network gave for them. Moves `layout` has no entry for get
+/// their share of the queen promotion's prior, as `PolicyLayout::prior_index` gives it.
+pub fn evaluations(positions: &[History], policy: &[f32], value: &[f32], layout: PolicyLayout) -> Vec<Evaluation> {
+    let entries = layout.planes() * 64;
+    positions
+        .iter()
+        .enumerate()
+        .map(|(i, position)| {
+            let board = position.board();
+            let flip = board.side_to_move() == Color::Black;
+            let logits = &policy[i * entries..(i + 1) * entries];
+
+            // softmax over the entries of the legal moves only, like the
+            // masked softmax of the TorchScript module
+            let moves = MoveGen::new_legal(&board).collect::<Vec<_>>();
+            let own = moves.iter().filter_map(|mov| layout.index(*mov, flip)).map(|idx| logits[idx]);
+            let max = own.clone().fold(f32::MIN, f32::max);
+            let total: f32 = own.map(|logit| (logit - max).exp()).sum();
+            let move_probabilities = moves
+                .into_iter()
+                .map(|mov| {
+                    let (idx, share) = layout.prior_index(mov, flip);
+                    (mov, (logits[idx] - max).exp() / total * share)
+                })
+                .collect();
+
+            (move_probabilities, value[i])
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::cache::NNCache;
+    use crate::eval::Evaluator;
+    use crate::mcts::{Proven, Root};
+
+    use std::str::FromStr;
+
+    /// A weights file holding `tensors`, each a name, a shape and the values.
+    pub(crate) fn encode(tensors: &[(String, Vec<usize>, Vec<f32>)]) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.extend(VERSION.to_le_bytes());
+        for (name, shape, values) in tensors {
+            data.extend((name.len() as u32).to_le_bytes());
+            data.extend(name.as_bytes());
+            data.extend((shape.len() as u32).to_le_bytes());
+            for dim in shape {
+                data.extend((*dim as u32).to_le_bytes());
+            }
+            for value in values {
+                data.extend(value.to_le_bytes());
+            }
+        }
+        data
+    }
+
+    fn tensor(name: &str, shape: &[usize]) -> (String, Vec<usize>, Vec<f32>) {
+        let count = shape.iter().product();
+        (name.to_string(), shape.to_vec(), vec![0.5; count])
+    }
+
+    /// A network of `blocks` residual blocks with convolutions of no bias and
+    /// batch norms with every parameter, as PyTorch's state dict has them.
+    fn network(planes: usize, blocks: usize, policy: usize) -> Vec<(String, Vec<usize>, Vec<f32>)> {
+        let filters = 4;
+        let conv = |name: &str, bn: &str, outputs: usize, inputs: usize, size: usize| {
+            let mut tensors = vec![tensor(&format!("{}.weight", name), &[outputs, inputs, size, size])];
+            for parameter in ["weight", "bias", "running_mean", "running_var"] {
+                tensors.push(tensor(&format!("{}.{}", bn, parameter), &[outputs]));
+            }
+            tensors.push((format!("{}.num_batches_tracked", bn), vec![], vec![0.0]));
+            tensors
+        };
+
+        let mut tensors = conv("input.conv", "input.bn", filters, planes, 3);
+        for i in 0..blocks {
+            tensors.extend(conv(&format!("residual.{}.conv1", i), &format!("residual.{}.bn1", i), filters, filters, 3));
+            tensors.extend(conv(&format!("residual.{}.conv2", i), &format!("residual.{}.bn2", i), filters, filters, 3));
+        }
+        tensors.extend(conv("policy.conv", "policy.bn", 2, filters, 1));
+        tensors.push(tensor("policy.fc.weight", &[policy * 64, 2 * 64]));
+        tensors.push(tensor("policy.fc.bias", &[policy * 64]));
+        tensors.extend(conv("value.conv", "value.bn", 1, filters, 1));
+        tensors.push(tensor("value.fc1.weight", &[8, 64]));
+        tensors.push(tensor("value.fc1.bias", &[8]));
+        tensors.push(tensor("value.fc2.weight", &[1, 8]));
+        tensors.push(tensor("value.fc2.bias", &[1]));
+        tensors
+    }
+
+    #[test]
+    fn detects_the_format_from_the_shapes() {
+        for (planes, input) in [(16, InputEncoding::Classic), (112, InputEncoding::Lc0)] {
+            for (policy, layout) in [(72, PolicyLayout::Classic), (73, PolicyLayout::AlphaZero)] {
+                let weights = Weights::parse(&encode(&network(planes, 2, policy))).unwrap();
+                assert_eq!(weights.format, NetworkFormat { input, policy: layout });
+                assert_eq!(weights.network.blocks(), 2);
+                assert_eq!(weights.network.filters(), 4);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        let error = |tensors: &[(String, Vec<usize>, Vec<f32>)]| Weights::parse(&encode(tensors)).err().unwrap();
+
+        let mut tensors = network(16, 1, 72);
+        tensors.retain(|(name, _, _)| name != "value.fc2.weight");
+        assert_eq!(error(&tensors), "missing value.fc2.weight");
+
+        let mut tensors = network(16, 1, 72);
+        tensors.push(tensor("residual.1.conv2.weight", &[4, 4, 3, 3]));
+        assert_eq!(error(&tensors), "unexpected tensor residual.1.conv2.weight");
+
+        let mut tensors = network(16, 1, 72);
+        tensors.push(tensor("input.bn.weight", &[4]));
+        assert_eq!(error(&tensors), "input.bn.weight is in the file twice");
+
+        assert_eq!(error(&network(17, 1, 72)), "no input encoding has 17 planes");
+        assert_eq!(error(&network(16, 1, 71)), "no policy layout has 4544 entries");
+
+        let data = encode(&network(16, 1, 72));
+        assert_eq!(Weights::parse(&data[..data.len() - 1]).err().unwrap(), "tensor value.fc2.bias is truncated");
+        assert_eq!(Weights::parse(b"DNNW\x02\0\0\0").err().unwrap(), "unsupported version 2");
+    }
+
+    /// A network giving every entry of the classic layout the same logit.
+    struct UniformClassic;
+
+    impl Evaluator for UniformClassic {
+        fn evaluate(&self, positions: &[History]) -> Vec<Evaluation> {
+            let policy = vec![0.0; positions.len() * PolicyLayout::Classic.planes() * 64];
+            evaluations(positions, &policy, &vec![0.0; positions.len()], PolicyLayout::Classic)
+        }
+    }
+
+    #[test]
+    fn classic_layout_finds_knight_promotion_mates() {
+        // only f8=N mates, and the classic layout has no entry for it
+        let history = History::from_fen("6bn/5Ppk/7p/8/8/8/8/K7 w - - 0 1").unwrap();
+        let cache = NNCache::new(0);
+        let root = Root::new(&history, &UniformClassic, false);
+        for _ in 0..100 {
+            root.parallel_rollouts(&history, &UniformClassic, &cache, 16);
+        }
+
+        assert_eq!(root.proven(), Proven::Win(1));
+        let best = root.root_node().max_n_select(root.nodes(), &[]).unwrap();
+        assert_eq!(best.mov, ChessMove::from_str("f7f8n").unwrap());
+    }
+}
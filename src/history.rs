@@ -0,0 +1,134 @@
+use chess::*;
+
+use std::str::FromStr;
+
+const DARK_SQUARES: BitBoard = BitBoard(0xAA55AA55AA55AA55);
+
+/// How many positions before the current one are kept for encoders that look
+/// at the game's history.
+pub const PREVIOUS_POSITIONS: usize = 7;
+
+/// Whether neither side has enough material left to ever checkmate: bare kings,
+/// a single minor piece, or only bishops that all stand on one square color.
+pub fn is_insufficient_material(board: &Board) -> bool {
+    let heavy = board.pieces(Piece::Pawn) | board.pieces(Piece::Rook) | board.pieces(Piece::Queen);
+    if heavy != EMPTY {
+        return false;
+    }
+
+    let knights = *board.pieces(Piece::Knight);
+    let bishops = *board.pieces(Piece::Bishop);
+    if (knights | bishops).popcnt() <= 1 {
+        return true;
+    }
+
+    knights == EMPTY && (bishops & DARK_SQUARES == EMPTY || bishops & !DARK_SQUARES == EMPTY)
+}
+
+/// The position before `pawn`, which just made a double step, was pushed.
+fn before_double_step(board: &Board, pawn: Square) -> Option<Board> {
+    let color = !board.side_to_move();
+    let from = match color {
+        Color::White => pawn.down()?.down()?,
+        Color::Black => pawn.up()?.up()?,
+    };
+
+    let mut builder = BoardBuilder::from(board);
+    builder
+        .clear_square(pawn)
+        .piece(from, Piece::Pawn, color)
+        .side_to_move(color)
+        .en_passant(None);
+    Board::try_from(&builder).ok()
+}
+
+/// A position together with what is needed to detect draws by repetition and
+/// by the fifty-move rule, neither of which `Board` keeps track of.
+#[derive(Clone, Debug)]
+pub struct History {
+    board: Board,
+    rule50: u32,
+    // hashes of the earlier positions since the last capture or pawn move,
+    // which are the only ones the current position can repeat
+    hashes: Vec<u64>,
+    // the last few positions before the current one, oldest first, each with
+    // whether it repeated an earlier position
+    previous: Vec<(Board, bool)>,
+}
+
+impl History {
+    pub fn new(board: Board, rule50: u32) -> Self {
+        Self {
+            board,
+            rule50,
+            hashes: vec![],
+            previous: vec![],
+        }
+    }
+
+    pub fn from_fen(fen: &str) -> Option<Self> {
+        let board = Board::from_str(fen).ok()?;
+        let rule50 = fen
+            .split_whitespace()
+            .nth(4)
+            .and_then(|halfmoves| halfmoves.parse().ok())
+            .unwrap_or(0);
+
+        let mut history = Self::new(board, rule50);
+        // the FEN says that a pawn just made a double step, so the position
+        // before it is known and shows the en passant capture to encoders
+        if let Some(previous) = board.en_passant().and_then(|pawn| before_double_step(&board, pawn)) {
+            history.previous.push((previous, false));
+        }
+
+        Some(history)
+    }
+
+    pub fn board(&self) -> Board {
+        self.board
+    }
+
+    /// Halfmoves since the last capture or pawn move.
+    pub fn rule50(&self) -> u32 {
+        self.rule50
+    }
+
+    /// The current position and the kept ones before it, most recent first,
+    /// each with whether it repeats an earlier position.
+    pub fn positions(&self) -> impl Iterator<Item = (Board, bool)> + '_ {
+        std::iter::once((self.board, self.is_repetition())).chain(self.previous.iter().rev().copied())
+    }
+
+    pub fn make_move(&mut self, mov: ChessMove) {
+        let is_pawn_move = self.board.piece_on(mov.get_source()) == Some(Piece::Pawn);
+        let is_capture = self.board.piece_on(mov.get_dest()).is_some();
+
+        if self.previous.len() == PREVIOUS_POSITIONS {
+            self.previous.remove(0);
+        }
+        self.previous.push((self.board, self.is_repetition()));
+
+        if is_pawn_move || is_capture {
+            self.rule50 = 0;
+            self.hashes.clear();
+        } else {
+            self.rule50 += 1;
+            self.hashes.push(self.board.get_hash());
+        }
+
+        self.board = self.board.make_move_new(mov);
+    }
+
+    /// Whether the current position has occurred before.
+    pub fn is_repetition(&self) -> bool {
+        self.hashes.contains(&self.board.get_hash())
+    }
+
+    /// Whether the game is drawn by repetition, by the fifty-move rule or for
+    /// lack of mating material. Any repetition counts, as the side that can
+    /// repeat once can repeat again. A checkmate delivered on the hundredth
+    /// halfmove still wins, so callers should look at `Board::status` first.
+    pub fn is_draw(&self) -> bool {
+        self.rule50 >= 100 || self.is_repetition() || is_insufficient_material(&self.board)
+    }
+}
@@ -0,0 +1,786 @@
+// an onnx-only build has no backend to run Lc0 networks on, and only names their files
+#![cfg_attr(not(any(feature = "torch", feature = "cpu")), allow(dead_code))]
+
+use crate::cache::Evaluation;
+#[cfg(feature = "torch")]
+use crate::encoding::encode_lc0_positions;
+use crate::history::History;
+
+use chess::*;
+use flate2::read::GzDecoder;
+#[cfg(feature = "torch")]
+use tch::{Kind, Tensor};
+
+use std::io::Read;
+use std::path::Path;
+use std::sync::OnceLock;
+
+// `Net.magic` of every Lc0 weights file
+const MAGIC: u32 = 0x1c0;
+// the enum values of `pblczero.Format` and `pblczero.NetworkFormat` this loader understands
+const ENCODING_LINEAR16: u64 = 1;
+const INPUT_CLASSICAL_112_PLANE: u64 = 1;
+const OUTPUT_WDL: u64 = 2;
+const NETWORK_SE: u64 = 2;
+const NETWORK_SE_WITH_HEADFORMAT: u64 = 4;
+const POLICY_CLASSICAL: u64 = 1;
+const VALUE_CLASSICAL: u64 = 1;
+const VALUE_WDL: u64 = 2;
+// Lc0 folds batch norm into the convolutions with this epsilon
+const BN_EPSILON: f32 = 1e-5;
+
+pub const POLICY_OUTPUTS: usize = 1858;
+
+/// A value of one protobuf field. Only the wire types of the weights format are read.
+#[derive(Clone, Copy)]
+enum Value<'a> {
+    Varint(u64),
+    Fixed32(u32),
+    // no field of the weights format has this type, it is only skipped
+    Fixed64,
+    Bytes(&'a [u8]),
+}
+
+fn read_varint(data: &mut &[u8]) -> Result<u64, String> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = data.split_first().ok_or("truncated varint")?;
+        *data = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err("varint too long".to_string())
+}
+
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8], String> {
+    if data.len() < len {
+        return Err("truncated field".to_string());
+    }
+
+    let (taken, rest) = data.split_at(len);
+    *data = rest;
+    Ok(taken)
+}
+
+/// The fields of one protobuf message, in file order.
+struct Message<'a> {
+    fields: Vec<(u64, Value<'a>)>,
+}
+
+impl<'a> Message<'a> {
+    fn parse(mut data: &'a [u8]) -> Result<Self, String> {
+        let mut fields = vec![];
+        while !data.is_empty() {
+            let key = read_varint(&mut data)?;
+            let value = match key & 7 {
+                0 => Value::Varint(read_varint(&mut data)?),
+                1 => {
+                    take(&mut data, 8)?;
+                    Value::Fixed64
+                }
+                2 => {
+                    let len = read_varint(&mut data)? as usize;
+                    Value::Bytes(take(&mut data, len)?)
+                }
+                5 => Value::Fixed32(u32::from_le_bytes(take(&mut data, 4)?.try_into().unwrap())),
+                wire_type => return Err(format!("unsupported wire type {}", wire_type)),
+            };
+            fields.push((key >> 3, value));
+        }
+
+        Ok(Self { fields })
+    }
+
+    /// The last value of `field`, which is what protobuf takes for non-repeated fields.
+    fn get(&self, field: u64) -> Option<Value<'a>> {
+        self.fields
+            .iter()
+            .rev()
+            .find(|(number, _)| *number == field)
+            .map(|(_, value)| *value)
+    }
+
+    fn varint(&self, field: u64) -> Option<u64> {
+        match self.get(field)? {
+            Value::Varint(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn fixed32(&self, field: u64) -> Option<u32> {
+        match self.get(field)? {
+            Value::Fixed32(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn message(&self, field: u64) -> Result<Option<Message<'a>>, String> {
+        match self.get(field) {
+            Some(Value::Bytes(data)) => Message::parse(data).map(Some),
+            Some(_) => Err(format!("field {} is not a message", field)),
+            None => Ok(None),
+        }
+    }
+
+    fn messages(&self, field: u64) -> Result<Vec<Message<'a>>, String> {
+        self.fields
+            .iter()
+            .filter(|(number, _)| *number == field)
+            .map(|(_, value)| match value {
+                Value::Bytes(data) => Message::parse(data),
+                _ => Err(format!("field {} is not a message", field)),
+            })
+            .collect()
+    }
+}
+
+/// Decode a `Weights.Layer`: 16-bit values spread linearly between its bounds.
+fn layer(weights: &Message, field: u64) -> Result<Vec<f32>, String> {
+    let Some(layer) = weights.message(field)? else {
+        return Ok(vec![]);
+    };
+
+    let min = f32::from_bits(layer.fixed32(1).unwrap_or(0));
+    let max = f32::from_bits(layer.fixed32(2).unwrap_or(0));
+    let params = match layer.get(3) {
+        Some(Value::Bytes(params)) => params,
+        _ => return Ok(vec![]),
+    };
+    if params.len() % 2 != 0 {
+        return Err("layer has an odd number of bytes".to_string());
+    }
+
+    Ok(params
+        .chunks_exact(2)
+        .map(|bytes| {
+            let value = u16::from_le_bytes([bytes[0], bytes[1]]) as f32 / u16::MAX as f32;
+            min + (max - min) * value
+        })
+        .collect())
+}
+
+/// A convolution with batch norm folded into its weights and biases.
+pub struct ConvWeights {
+    // [outputs, inputs, size, size]
+    pub weights: Vec<f32>,
+    pub biases: Vec<f32>,
+}
+
+impl ConvWeights {
+    fn parse(weights: &Message, field: u64) -> Result<Self, String> {
+        let block = weights
+            .message(field)?
+            .ok_or_else(|| format!("missing convolution {}", field))?;
+        // despite the name, newer files store the variance in layer 4
+        Self::fold(
+            layer(&block, 1)?,
+            layer(&block, 2)?,
+            layer(&block, 3)?,
+            layer(&block, 4)?,
+            layer(&block, 5)?,
+            layer(&block, 6)?,
+        )
+        .map_err(|err| format!("convolution {} {}", field, err))
+    }
+
+    /// Fold batch norm into a convolution's weights and biases. Any of the
+    /// biases and batch norm parameters may be empty, which leaves them out.
+    pub fn fold(
+        mut conv_weights: Vec<f32>,
+        mut biases: Vec<f32>,
+        mut means: Vec<f32>,
+        variances: Vec<f32>,
+        mut gammas: Vec<f32>,
+        mut betas: Vec<f32>,
+    ) -> Result<Self, String> {
+        let outputs = biases.len().max(means.len());
+        if outputs == 0 || conv_weights.is_empty() || !conv_weights.len().is_multiple_of(outputs) {
+            return Err("has no valid shape".to_string());
+        }
+        if biases.is_empty() {
+            biases = vec![0.0; outputs];
+        }
+        if !means.is_empty() {
+            // old files have no gammas or betas, and either may be left out on its own
+            if gammas.is_empty() {
+                gammas = vec![1.0; outputs];
+            }
+            if betas.is_empty() {
+                betas = vec![0.0; outputs];
+            }
+            if [&biases, &means, &variances, &gammas, &betas].iter().any(|v| v.len() != outputs) {
+                return Err("has batch norm of the wrong size".to_string());
+            }
+
+            let inputs = conv_weights.len() / outputs;
+            for o in 0..outputs {
+                let gamma = gammas[o] / (variances[o] + BN_EPSILON).sqrt();
+                means[o] -= biases[o];
+                for weight in &mut conv_weights[o * inputs..(o + 1) * inputs] {
+                    *weight *= gamma;
+                }
+                biases[o] = betas[o] - gamma * means[o];
+            }
+        }
+
+        Ok(Self {
+            weights: conv_weights,
+            biases,
+        })
+    }
+}
+
+/// The squeeze-and-excitation unit at the end of a residual block.
+pub struct SeWeights {
+    pub w1: Vec<f32>,
+    pub b1: Vec<f32>,
+    pub w2: Vec<f32>,
+    pub b2: Vec<f32>,
+}
+
+pub struct ResidualWeights {
+    pub conv1: ConvWeights,
+    pub conv2: ConvWeights,
+    pub se: Option<SeWeights>,
+}
+
+/// The parameters of an Lc0 network with a residual tower, optional
+/// squeeze-and-excitation, and the classical policy and value heads. Exported
+/// networks of the engine's own format have the same layers and use it too.
+pub struct Weights {
+    pub input: ConvWeights,
+    pub residual: Vec<ResidualWeights>,
+    pub policy: ConvWeights,
+    pub ip_pol_w: Vec<f32>,
+    pub ip_pol_b: Vec<f32>,
+    pub value: ConvWeights,
+    pub ip1_val_w: Vec<f32>,
+    pub ip1_val_b: Vec<f32>,
+    pub ip2_val_w: Vec<f32>,
+    pub ip2_val_b: Vec<f32>,
+    // whether the value head gives win, draw and loss probabilities instead of a single value
+    pub wdl: bool,
+}
+
+impl Weights {
+    /// Read a weights file, gzipped or not.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let data = std::fs::read(path).map_err(|err| format!("cannot read '{}': {}", path, err))?;
+        let data = if data.starts_with(&[0x1f, 0x8b]) {
+            let mut unpacked = vec![];
+            GzDecoder::new(&data[..])
+                .read_to_end(&mut unpacked)
+                .map_err(|err| format!("'{}' is not a valid gzip file: {}", path, err))?;
+            unpacked
+        } else {
+            data
+        };
+
+        Self::parse(&data).map_err(|err| format!("'{}' is not a supported Lc0 network: {}", path, err))
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        let net = Message::parse(data)?;
+        if net.fixed32(1) != Some(MAGIC) {
+            return Err("wrong magic number".to_string());
+        }
+
+        // files from before the format was recorded are classical networks
+        let format = net.message(4)?;
+        let encoding = format.as_ref().and_then(|format| format.varint(1)).unwrap_or(ENCODING_LINEAR16);
+        if encoding != ENCODING_LINEAR16 {
+            return Err(format!("unsupported weights encoding {}", encoding));
+        }
+        let network_format = match &format {
+            Some(format) => format.message(2)?,
+            None => None,
+        };
+        let field = |number| network_format.as_ref().and_then(|format| format.varint(number));
+        let input = field(1).unwrap_or(INPUT_CLASSICAL_112_PLANE);
+        let output = field(2).unwrap_or(0);
+        let network = field(3).unwrap_or(0);
+        let policy = field(4).unwrap_or(POLICY_CLASSICAL);
+        let value = field(5).unwrap_or(if output == OUTPUT_WDL { VALUE_WDL } else { VALUE_CLASSICAL });
+        if input != INPUT_CLASSICAL_112_PLANE {
+            return Err(format!("unsupported input format {}", input));
+        }
+        if network > NETWORK_SE_WITH_HEADFORMAT {
+            return Err(format!("unsupported network structure {}", network));
+        }
+        if policy != POLICY_CLASSICAL {
+            return Err(format!("unsupported policy head {}", policy));
+        }
+        if value != VALUE_CLASSICAL && value != VALUE_WDL {
+            return Err(format!("unsupported value head {}", value));
+        }
+
+        let weights = net.message(10)?.ok_or("no weights")?;
+        let residual = weights
+            .messages(2)?
+            .iter()
+            .map(|block| {
+                let se = match block.message(3)? {
+                    Some(se) => Some(SeWeights {
+                        w1: layer(&se, 1)?,
+                        b1: layer(&se, 2)?,
+                        w2: layer(&se, 3)?,
+                        b2: layer(&se, 4)?,
+                    }),
+                    None => None,
+                };
+                Ok(ResidualWeights {
+                    conv1: ConvWeights::parse(block, 1)?,
+                    conv2: ConvWeights::parse(block, 2)?,
+                    se,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        if (network == NETWORK_SE || network == NETWORK_SE_WITH_HEADFORMAT)
+            && residual.iter().any(|block| block.se.is_none())
+        {
+            return Err("residual block without squeeze-and-excitation unit".to_string());
+        }
+
+        Ok(Self {
+            input: ConvWeights::parse(&weights, 1)?,
+            residual,
+            policy: ConvWeights::parse(&weights, 3)?,
+            ip_pol_w: layer(&weights, 4)?,
+            ip_pol_b: layer(&weights, 5)?,
+            value: ConvWeights::parse(&weights, 6)?,
+            ip1_val_w: layer(&weights, 7)?,
+            ip1_val_b: layer(&weights, 8)?,
+            ip2_val_w: layer(&weights, 9)?,
+            ip2_val_b: layer(&weights, 10)?,
+            wdl: value == VALUE_WDL,
+        })
+    }
+
+    pub fn blocks(&self) -> usize {
+        self.residual.len()
+    }
+
+    pub fn filters(&self) -> usize {
+        self.input.biases.len()
+    }
+
+    pub fn has_se(&self) -> bool {
+        self.residual.iter().any(|block| block.se.is_some())
+    }
+
+    pub fn wdl(&self) -> bool {
+        self.wdl
+    }
+}
+
+/// The index of `mov`, with the board flipped for black, among Lc0's policy
+/// outputs: every queen and knight move from every square in square order,
+/// each sorted by destination, then the underpromotions to queen, rook and
+/// bishop. A promotion to a knight uses the plain pawn move.
+pub fn policy_index(mov: ChessMove, flip: bool) -> usize {
+    static TABLE: OnceLock<Vec<u16>> = OnceLock::new();
+    let table = TABLE.get_or_init(|| {
+        let mut table = vec![u16::MAX; 64 * 64];
+        let mut index = 0;
+        for from in 0..64i32 {
+            for to in 0..64i32 {
+                let (ranks, files) = (to / 8 - from / 8, to % 8 - from % 8);
+                let queen = (ranks == 0 || files == 0 || ranks.abs() == files.abs()) && from != to;
+                let knight = ranks.abs() * files.abs() == 2;
+                if queen || knight {
+                    table[(from * 64 + to) as usize] = index;
+                    index += 1;
+                }
+            }
+        }
+
+        table
+    });
+
+    let square = |sq: Square| if flip { sq.to_index() ^ 0x38 } else { sq.to_index() };
+    let (from, to) = (square(mov.get_source()), square(mov.get_dest()));
+    let promotion = match mov.get_promotion() {
+        Some(Piece::Queen) => 0,
+        Some(Piece::Rook) => 1,
+        Some(Piece::Bishop) => 2,
+        _ => return table[from * 64 + to] as usize,
+    };
+
+    // the destinations before this one among the promotions from the files to the left
+    let file = from % 8;
+    let earlier = if file == 0 { 0 } else { 2 + 3 * (file - 1) };
+    let destination = earlier + to % 8 - file.saturating_sub(1);
+    POLICY_OUTPUTS - 66 + destination * 3 + promotion
+}
+
+#[cfg(feature = "torch")]
+fn tensor(values: &[f32], shape: &[i64]) -> Tensor {
+    Tensor::of_slice(values).reshape(shape)
+}
+
+#[cfg(feature = "torch")]
+struct Conv {
+    weights: Tensor,
+    biases: Tensor,
+    padding: i64,
+}
+
+#[cfg(feature = "torch")]
+impl Conv {
+    fn new(conv: &ConvWeights, inputs: usize) -> Result<Self, String> {
+        let outputs = conv.biases.len();
+        let size = ((conv.weights.len() / (outputs * inputs)) as f64).sqrt() as usize;
+        if size * size * outputs * inputs != conv.weights.len() {
+            return Err(format!("convolution with {} inputs has the wrong number of weights", inputs));
+        }
+
+        Ok(Self {
+            weights: tensor(&conv.weights, &[outputs as i64, inputs as i64, size as i64, size as i64]),
+            biases: tensor(&conv.biases, &[outputs as i64]),
+            padding: size as i64 / 2,
+        })
+    }
+
+    fn outputs(&self) -> usize {
+        self.biases.size()[0] as usize
+    }
+
+    fn forward(&self, x: &Tensor) -> Tensor {
+        x.conv2d(&self.weights, Some(&self.biases), &[1, 1], &[self.padding, self.padding], &[1, 1], 1)
+    }
+}
+
+#[cfg(feature = "torch")]
+struct Linear {
+    weights: Tensor,
+    biases: Tensor,
+}
+
+#[cfg(feature = "torch")]
+impl Linear {
+    fn new(weights: &[f32], biases: &[f32], inputs: usize) -> Result<Self, String> {
+        if biases.is_empty() || weights.len() != biases.len() * inputs {
+            return Err(format!("fully connected layer with {} inputs has the wrong number of weights", inputs));
+        }
+
+        Ok(Self {
+            weights: tensor(weights, &[biases.len() as i64, inputs as i64]),
+            biases: tensor(biases, &[biases.len() as i64]),
+        })
+    }
+
+    fn forward(&self, x: &Tensor) -> Tensor {
+        x.linear(&self.weights, Some(&self.biases))
+    }
+}
+
+#[cfg(feature = "torch")]
+struct Se {
+    fc1: Linear,
+    fc2: Linear,
+}
+
+#[cfg(feature = "torch")]
+impl Se {
+    /// Scale and shift the channels of `x` by what the unit makes of their averages.
+    fn forward(&self, x: &Tensor) -> Tensor {
+        let channels = x.size()[1];
+        let pooled = x.mean_dim(Some([2, 3].as_slice()), false, Kind::Float);
+        let out = self.fc2.forward(&self.fc1.forward(&pooled).relu());
+        let gammas = out.narrow(1, 0, channels).sigmoid().view([-1, channels, 1, 1]);
+        let betas = out.narrow(1, channels, channels).view([-1, channels, 1, 1]);
+        x * gammas + betas
+    }
+}
+
+#[cfg(feature = "torch")]
+struct Residual {
+    conv1: Conv,
+    conv2: Conv,
+    se: Option<Se>,
+}
+
+/// An Lc0 network run with tch ops on the CPU.
+#[cfg(feature = "torch")]
+// with the pure Rust backend built in, this is only left for its parity test
+#[cfg_attr(feature = "cpu", allow(dead_code))]
+pub struct Network {
+    input: Conv,
+    residual: Vec<Residual>,
+    policy: Conv,
+    policy_fc: Linear,
+    value: Conv,
+    value_fc1: Linear,
+    value_fc2: Linear,
+    wdl: bool,
+}
+
+#[cfg(feature = "torch")]
+// SAFETY: the tensors are only read once the network is built, and libtorch
+// allows reading a tensor from several threads, as tch assumes for `CModule`
+unsafe impl Sync for Network {}
+
+#[cfg(feature = "torch")]
+#[cfg_attr(feature = "cpu", allow(dead_code))]
+impl Network {
+    pub fn new(weights: &Weights) -> Result<Self, String> {
+        let input = Conv::new(&weights.input, 112)?;
+        let filters = input.outputs();
+        let residual = weights
+            .residual
+            .iter()
+            .map(|block| {
+                let se = match &block.se {
+                    Some(se) => Some(Se {
+                        fc1: Linear::new(&se.w1, &se.b1, filters)?,
+                        fc2: Linear::new(&se.w2, &se.b2, se.b1.len())?,
+                    }),
+                    None => None,
+                };
+                Ok(Residual {
+                    conv1: Conv::new(&block.conv1, filters)?,
+                    conv2: Conv::new(&block.conv2, filters)?,
+                    se,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let policy = Conv::new(&weights.policy, filters)?;
+        let policy_fc = Linear::new(&weights.ip_pol_w, &weights.ip_pol_b, policy.outputs() * 64)?;
+        if weights.ip_pol_b.len() != POLICY_OUTPUTS {
+            return Err(format!("policy head has {} outputs instead of {}", weights.ip_pol_b.len(), POLICY_OUTPUTS));
+        }
+        let value = Conv::new(&weights.value, filters)?;
+        let value_fc1 = Linear::new(&weights.ip1_val_w, &weights.ip1_val_b, value.outputs() * 64)?;
+        let value_fc2 = Linear::new(&weights.ip2_val_w, &weights.ip2_val_b, weights.ip1_val_b.len())?;
+
+        Ok(Self {
+            input,
+            residual,
+            policy,
+            policy_fc,
+            value,
+            value_fc1,
+            value_fc2,
+            wdl: weights.wdl,
+        })
+    }
+
+    /// The policy logits and the value for a batch of encoded positions.
+    fn forward(&self, planes: &Tensor) -> (Tensor, Tensor) {
+        let mut x = self.input.forward(planes).relu();
+        for block in &self.residual {
+            let mut y = block.conv2.forward(&block.conv1.forward(&x).relu());
+            if let Some(se) = &block.se {
+                y = se.forward(&y);
+            }
+            x = (y + &x).relu();
+        }
+
+        let policy = self.policy_fc.forward(&self.policy.forward(&x).relu().flatten(1, -1));
+        let value = self.value.forward(&x).relu().flatten(1, -1);
+        let value = self.value_fc2.forward(&self.value_fc1.forward(&value).relu());
+        let value = if self.wdl {
+            let wdl = value.softmax(1, Kind::Float);
+            wdl.select(1, 0) - wdl.select(1, 2)
+        } else {
+            value.tanh().flatten(0, -1)
+        };
+
+        (policy, value)
+    }
+
+    pub fn evaluate(&self, positions: &[History]) -> Vec<Evaluation> {
+        let planes = Tensor::try_from(encode_lc0_positions(positions)).unwrap();
+        let (policy, value) = tch::no_grad(|| self.forward(&planes));
+        evaluations(positions, &Vec::<f32>::from(&policy), &Vec::<f32>::from(&value))
+    }
+}
+
+/// The priors and values of `positions` from the policy logits and the values
+/// the network gave for them.
+pub fn evaluations(positions: &[History], policy: &[f32], value: &[f32]) -> Vec<Evaluation> {
+    positions
+        .iter()
+        .enumerate()
+        .map(|(i, position)| {
+            let board = position.board();
+            let flip = board.side_to_move() == Color::Black;
+            let logits = &policy[i * POLICY_OUTPUTS..(i + 1) * POLICY_OUTPUTS];
+
+            // softmax over the legal moves only
+            let moves = MoveGen::new_legal(&board)
+                .map(|mov| (mov, logits[policy_index(mov, flip)]))
+                .collect::<Vec<_>>();
+            let max = moves.iter().map(|(_, logit)| *logit).fold(f32::MIN, f32::max);
+            let mut move_probabilities = moves
+                .into_iter()
+                .map(|(mov, logit)| (mov, (logit - max).exp()))
+                .collect::<Vec<_>>();
+            let total: f32 = move_probabilities.iter().map(|(_, p)| p).sum();
+            for (_, p) in move_probabilities.iter_mut() {
+                *p /= total;
+            }
+
+            (move_probabilities, value[i])
+        })
+        .collect()
+}
+
+/// Whether `path` names an Lc0 weights file rather than a TorchScript module.
+pub fn is_weights_file(path: &str) -> bool {
+    let name = Path::new(path).file_name().and_then(|name| name.to_str()).unwrap_or("");
+    name.ends_with(".pb") || name.ends_with(".pb.gz")
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use std::str::FromStr;
+
+    fn varint(mut value: u64) -> Vec<u8> {
+        let mut bytes = vec![];
+        while value >= 0x80 {
+            bytes.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        bytes.push(value as u8);
+        bytes
+    }
+
+    #[cfg(feature = "cpu")]
+    pub(crate) fn varint_field(number: u64, value: u64) -> Vec<u8> {
+        [varint(number << 3), varint(value)].concat()
+    }
+
+    pub(crate) fn fixed32_field(number: u64, value: u32) -> Vec<u8> {
+        [varint(number << 3 | 5), value.to_le_bytes().to_vec()].concat()
+    }
+
+    /// Encode `data` as a length delimited field.
+    pub(crate) fn field(number: u64, data: &[u8]) -> Vec<u8> {
+        let mut bytes = varint(number << 3 | 2);
+        bytes.extend(varint(data.len() as u64));
+        bytes.extend(data);
+        bytes
+    }
+
+    /// The bounds and 16-bit params `values` are stored as in a `Weights.Layer`.
+    fn linear16(values: &[f32]) -> (f32, f32, Vec<u16>) {
+        let min = values.iter().copied().fold(f32::MAX, f32::min);
+        let max = values.iter().copied().fold(f32::MIN, f32::max).max(min + 1e-6);
+        let params = values
+            .iter()
+            .map(|value| ((value - min) / (max - min) * u16::MAX as f32).round() as u16)
+            .collect();
+        (min, max, params)
+    }
+
+    /// `values` as they read back from a layer.
+    #[cfg(feature = "cpu")]
+    pub(crate) fn quantize(values: &[f32]) -> Vec<f32> {
+        let (min, max, params) = linear16(values);
+        params
+            .into_iter()
+            .map(|param| min + (max - min) * (param as f32 / u16::MAX as f32))
+            .collect()
+    }
+
+    /// Encode `values` as a `Weights.Layer` field, as precisely as 16 bits allow.
+    pub(crate) fn layer(number: u64, values: &[f32]) -> Vec<u8> {
+        let (min, max, params) = linear16(values);
+        let params = params.into_iter().flat_map(u16::to_le_bytes).collect::<Vec<_>>();
+        let layer = [fixed32_field(1, min.to_bits()), fixed32_field(2, max.to_bits()), field(3, &params)].concat();
+        field(number, &layer)
+    }
+
+    /// A weights file for an SE network with the classical heads around the
+    /// encoded `Net.weights` message.
+    #[cfg(feature = "cpu")]
+    pub(crate) fn net(wdl: bool, weights: &[u8]) -> Vec<u8> {
+        let value = if wdl { VALUE_WDL } else { VALUE_CLASSICAL };
+        let network_format = [
+            varint_field(1, INPUT_CLASSICAL_112_PLANE),
+            varint_field(3, NETWORK_SE_WITH_HEADFORMAT),
+            varint_field(4, POLICY_CLASSICAL),
+            varint_field(5, value),
+        ]
+        .concat();
+        let format = [varint_field(1, ENCODING_LINEAR16), field(2, &network_format)].concat();
+        [fixed32_field(1, MAGIC), field(4, &format), field(10, weights)].concat()
+    }
+
+    #[test]
+    fn policy_indices_match_lc0() {
+        let index = |mov: &str, flip| policy_index(ChessMove::from_str(mov).unwrap(), flip);
+        assert_eq!(index("e2e4", false), 322);
+        assert_eq!(index("d2d4", false), 293);
+        assert_eq!(index("g1f3", false), 159);
+        assert_eq!(index("a7a8q", false), 1792);
+        assert_eq!(index("h7h8b", false), 1857);
+        // knight promotions are the plain pawn moves
+        assert_eq!(index("b7a8n", false), index("b7a8", false));
+        // black's moves are seen from their side of the board
+        assert_eq!(index("e7e5", true), 322);
+        assert_eq!(index("g8f6", true), 159);
+        assert_eq!(index("h2h1b", true), 1857);
+    }
+
+    #[test]
+    fn rejects_truncated_messages() {
+        // a varint key whose continuation bit promises another byte
+        assert_eq!(Message::parse(&[0x88]).err().unwrap(), "truncated varint");
+        // a field claiming more bytes than there are
+        assert_eq!(Message::parse(&[0x0a, 0x05, 1, 2]).err().unwrap(), "truncated field");
+        assert_eq!(Message::parse(&[0x0d, 1, 2, 3]).err().unwrap(), "truncated field");
+        assert!(Message::parse(&[0x0a, 0x02, 1, 2]).is_ok());
+    }
+
+    #[test]
+    fn folds_batch_norm_with_missing_gammas_or_betas() {
+        // two outputs of a 1x1 convolution over two inputs
+        let weights = [0.5, -1.0, 2.0, 0.25];
+        let biases = [0.1, -0.2];
+        let means = [0.3, -0.4];
+        let variances = [4.0, 0.25];
+        let gammas = [1.5, -0.5];
+        let betas = [0.2, 0.6];
+
+        for (has_gammas, has_betas) in [(false, false), (true, false), (false, true), (true, true)] {
+            let mut block = [layer(1, &weights), layer(2, &biases), layer(3, &means), layer(4, &variances)].concat();
+            if has_gammas {
+                block.extend(layer(5, &gammas));
+            }
+            if has_betas {
+                block.extend(layer(6, &betas));
+            }
+            let data = field(7, &block);
+            let conv = ConvWeights::parse(&Message::parse(&data).unwrap(), 7).unwrap();
+
+            for o in 0..2 {
+                let gamma = if has_gammas { gammas[o] } else { 1.0 };
+                let beta = if has_betas { betas[o] } else { 0.0 };
+                let scale = gamma / (variances[o] + BN_EPSILON).sqrt();
+                for i in 0..2 {
+                    let expected = weights[o * 2 + i] * scale;
+                    let actual = conv.weights[o * 2 + i];
+                    assert!((expected - actual).abs() < 1e-3, "weight {} instead of {}", actual, expected);
+                }
+                let expected = beta - scale * (means[o] - biases[o]);
+                let actual = conv.biases[o];
+                assert!(
+                    (expected - actual).abs() < 1e-3,
+                    "bias {} instead of {} with gammas {} and betas {}",
+                    actual,
+                    expected,
+                    has_gammas,
+                    has_betas
+                );
+            }
+        }
+    }
+}
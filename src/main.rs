@@ -1,319 +1,692 @@
 use chess::*;
 use std::{
     io::{self, BufRead},
-    str::FromStr,
-    sync::atomic::{AtomicBool, Ordering},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
     time::{Duration, Instant},
     sync::{mpsc, Arc},
     thread
 };
 
-use vampirc_uci::{UciPiece, UciTimeControl, parse_one, UciMessage};
+use vampirc_uci::{UciMove, UciPiece, UciSearchControl, UciTimeControl, parse_one, UciMessage};
 
+mod arena;
+mod cache;
 mod config;
+#[cfg(feature = "cpu")]
+mod cpu;
+mod eval;
+#[cfg(feature = "cpu")]
+mod export;
+mod history;
+mod lc0;
 mod mcts;
+#[cfg(feature = "onnx")]
+mod onnx;
+mod options;
+mod time_manager;
+mod uci_engine;
 pub mod encoding;
 
+#[cfg(not(any(feature = "torch", feature = "cpu", feature = "onnx")))]
+compile_error!("build with at least one of the torch, cpu and onnx features to have a network backend");
+
 use config::*;
+use eval::{Evaluator, ExternalEvaluator, MockEvaluator};
+use history::History;
+use options::Options;
+use time_manager::TimeBudget;
 pub use encoding::*;
 
+/// Work handed from the UCI loop to the search worker.
+enum Request {
+    Search(Box<SearchRequest>),
+    /// `ucinewgame`: drop the previous game's tree and switch to the configured
+    /// network if it changed, sending on the channel once done.
+    NewGame(Options, mpsc::Sender<()>),
+}
+
+/// A `go` command handed from the UCI loop to the search worker.
+struct SearchRequest {
+    history: History,
+    time_control: Option<UciTimeControl>,
+    search_control: Option<UciSearchControl>,
+    // `go ponder`: search without a time limit until `ponderhit` or `stop`.
+    // Set while the search waits for `ponderhit`, and per search like `should_stop`
+    pondering: Arc<AtomicBool>,
+    options: Options,
+    // set by `stop`; each search has its own, so that a `stop` the worker has
+    // not seen yet cannot leak into the next search or be cleared by it
+    should_stop: Arc<AtomicBool>,
+}
+
+/// The network the search runs on, loaded from a file that can be changed between searches.
+struct Network {
+    model: Option<Arc<dyn Evaluator>>,
+    // what kind of network the current model is
+    description: String,
+    // the file the current model came from, or that failed to load
+    path: String,
+    error: Option<String>,
+}
+
+impl Network {
+    fn new(path: &str) -> Self {
+        let mut network = Self {
+            model: None,
+            description: String::new(),
+            path: String::new(),
+            error: None,
+        };
+        network.switch(path);
+        network
+    }
+
+    /// Load the network at `path` unless that file was tried already. Returns
+    /// whether a load was attempted; on failure the previous model is kept.
+    fn switch(&mut self, path: &str) -> bool {
+        if path == self.path {
+            return false;
+        }
+
+        self.path = path.to_string();
+        match eval::load_network(path) {
+            Ok((model, description)) => {
+                self.model = Some(model);
+                self.description = description;
+                self.error = None;
+            }
+            Err(err) => self.error = Some(err),
+        }
+        true
+    }
+
+    fn report(&self) {
+        match &self.error {
+            Some(err) => println!("info string {}", err),
+            None => println!("info string using network '{}' ({})", self.path, self.description),
+        }
+    }
+}
+
+/// The network to use when the `WeightsFile` option is empty: the `--weights`
+/// command line argument, else the environment variable, else `MODEL`.
+fn default_weights_file() -> String {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--weights" || arg == "-w" {
+            if let Some(path) = args.next() {
+                return path;
+            }
+        } else if let Some(path) = arg.strip_prefix("--weights=") {
+            return path.to_string();
+        }
+    }
+
+    std::env::var(WEIGHTS_ENV).unwrap_or_else(|_| MODEL.to_string())
+}
+
+/// Body of the additional search threads: keep feeding batches into the shared
+/// tree until the main search thread decides the search is over.
+fn helper_search(
+    root: &mcts::Root,
+    history: &History,
+    evaluator: &dyn Evaluator,
+    cache: &cache::NNCache,
+    batch_size: usize,
+    rollouts: &AtomicUsize,
+    finished: &AtomicBool,
+) {
+    while !finished.load(Ordering::Relaxed) && !root.is_full() {
+        root.parallel_rollouts(history, evaluator, cache, batch_size);
+        rollouts.fetch_add(batch_size, Ordering::Relaxed);
+    }
+}
+
+/// Convert a move as parsed by vampirc-uci into a `ChessMove`.
+fn to_chess_move(mov: &UciMove) -> ChessMove {
+    let from = mov.from;
+    let to = mov.to;
+
+    let from = Square::make_square(
+        match from.rank {
+            1 => Rank::First,
+            2 => Rank::Second,
+            3 => Rank::Third,
+            4 => Rank::Fourth,
+            5 => Rank::Fifth,
+            6 => Rank::Sixth,
+            7 => Rank::Seventh,
+            8 => Rank::Eighth,
+            _ => unreachable!(),
+        },
+        match from.file {
+            'a' => File::A,
+            'b' => File::B,
+            'c' => File::C,
+            'd' => File::D,
+            'e' => File::E,
+            'f' => File::F,
+            'g' => File::G,
+            'h' => File::H,
+            _ => unreachable!(),
+        },
+    );
+
+    let to = Square::make_square(
+        match to.rank {
+            1 => Rank::First,
+            2 => Rank::Second,
+            3 => Rank::Third,
+            4 => Rank::Fourth,
+            5 => Rank::Fifth,
+            6 => Rank::Sixth,
+            7 => Rank::Seventh,
+            8 => Rank::Eighth,
+            _ => unreachable!(),
+        },
+        match to.file {
+            'a' => File::A,
+            'b' => File::B,
+            'c' => File::C,
+            'd' => File::D,
+            'e' => File::E,
+            'f' => File::F,
+            'g' => File::G,
+            'h' => File::H,
+            _ => unreachable!(),
+        },
+    );
+    ChessMove::new(
+        from,
+        to,
+        mov.promotion.map(|piece| match piece {
+            UciPiece::Pawn => Piece::Pawn,
+            UciPiece::Knight => Piece::Knight,
+            UciPiece::Bishop => Piece::Bishop,
+            UciPiece::Rook => Piece::Rook,
+            UciPiece::Queen => Piece::Queen,
+            UciPiece::King => Piece::King,
+        }),
+    )
+}
+
+/// The `score` part of an info line, given q and the proven result for the side to move.
+fn format_score(q: f32, proven: mcts::Proven) -> String {
+    match proven {
+        mcts::Proven::Win(plies) => format!("mate {}", plies.div_ceil(2)),
+        mcts::Proven::Loss(plies) => format!("mate {}", -((plies / 2) as i32)),
+        mcts::Proven::Draw => "cp 0".to_string(),
+        mcts::Proven::Unknown => {
+            let q = q * 2.0 - 1.0;
+            let score = -(q.signum() * (1.0 - q.abs()).ln() / (1.2f32).ln()) * 100.0 / 2.0;
+            format!("cp {}", score as i32)
+        }
+    }
+}
+
+fn format_pv(pv: &[ChessMove]) -> String {
+    pv.iter()
+        .map(|mov| format!("{}", mov))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Print the `info` lines for the current state of the search. With `multipv`
+/// above one, the most visited root moves get a line each, best first. Moves
+/// no rollout has come back from yet have no score and are left out.
+fn print_info(root: &mcts::Root, multipv: usize, nodes: usize, elapsed: Duration) {
+    let nps = nodes as u32 / elapsed.as_secs().max(1) as u32;
+
+    if multipv <= 1 {
+        let pv = root.principal_variation(root.root_index(), root.search_moves());
+        println!(
+            "info currmove {} depth {} seldepth {} score {} nodes {} nps {} hashfull {} time {} pv {}",
+            pv[0],
+            root.depth(),
+            root.seldepth(),
+            format_score(root.get_q(), root.proven()),
+            nodes,
+            nps,
+            root.hashfull(),
+            elapsed.as_millis(),
+            format_pv(&pv)
+        );
+        return;
+    }
+
+    let mut edges = root.search_edges().filter(|edge| edge.get_visits() > 0.0).collect::<Vec<_>>();
+    edges.sort_by(|a, b| b.get_visits().partial_cmp(&a.get_visits()).unwrap());
+    for (i, edge) in edges.into_iter().take(multipv).enumerate() {
+        let mut pv = vec![edge.mov];
+        if let Some(child) = edge.child_index() {
+            pv.extend(root.principal_variation(child, &[]));
+        }
+
+        println!(
+            "info multipv {} depth {} seldepth {} score {} nodes {} nps {} hashfull {} time {} pv {}",
+            i + 1,
+            root.depth(),
+            root.seldepth(),
+            format_score(edge.get_reported_q(root.nodes()), edge.proven(root.nodes()).flip()),
+            nodes,
+            nps,
+            root.hashfull(),
+            elapsed.as_millis(),
+            format_pv(&pv)
+        );
+    }
+}
+
+/// Search each of `BENCH_POSITIONS` for `nodes` rollouts on one thread with
+/// the mock evaluator, and report the speed and memory use of the tree alone.
+fn bench(nodes: usize, batch_size: usize) {
+    let cache = cache::NNCache::new(0);
+    let mut total_rollouts = 0;
+    let mut total_nodes = 0;
+    let mut total_memory = 0;
+    let start = Instant::now();
+    for fen in BENCH_POSITIONS {
+        let history = History::from_fen(fen).unwrap();
+        let root = mcts::Root::new(&history, &MockEvaluator, false);
+        let mut rollouts = 0;
+        while rollouts < nodes {
+            root.parallel_rollouts(&history, &MockEvaluator, &cache, batch_size);
+            rollouts += batch_size;
+        }
+
+        total_rollouts += rollouts;
+        total_nodes += root.node_count();
+        total_memory += root.memory();
+    }
+
+    let elapsed = start.elapsed();
+    println!(
+        "info string bench rollouts {} nodes {} time {} rps {} bytes/node {}",
+        total_rollouts,
+        total_nodes,
+        elapsed.as_millis(),
+        (total_rollouts as f32 / elapsed.as_secs_f32()) as usize,
+        total_memory / total_nodes.max(1)
+    );
+}
+
 fn main() {
     eprintln!("Divine 0.1 compiled on rustc 1.67.0-nightly (09508489e 2022-11-04)");
+    #[cfg(feature = "torch")]
     eprintln!(
         "Current libtorch intra-op threads: {}",
         tch::get_num_threads()
     );
 
-    let mut board = Game::new();
-    let mut model = tch::CModule::load(MODEL).expect("model is in path");
-    model.set_eval();
-    let model = Arc::new(model);
+    let mut history = History::new(Board::default(), 0);
+    let mut options = Options::new();
+    let default_weights = default_weights_file();
+    let weights_file = move |options: &Options| match options.weights_file() {
+        "" => default_weights.clone(),
+        path => path.to_string(),
+    };
 
-    eprintln!("Using network: '{}'\n", MODEL);
+    let mut network = Network::new(&weights_file(&options));
+    match &network.error {
+        Some(err) => eprintln!("{}\n", err),
+        None => eprintln!("Using network: '{}'\n", network.path),
+    }
 
     // worker
     let (tx, rx) = mpsc::channel();
-    let should_stop = Arc::new(AtomicBool::new(false));
+    // the stop flag of the last search handed to the worker
+    let mut should_stop = Arc::new(AtomicBool::new(false));
+    // the pondering flag of the last search handed to the worker
+    let mut pondering = Arc::new(AtomicBool::new(false));
 
     {
-        let model = model.clone();
-        let should_stop = should_stop.clone();
         thread::spawn(move || {
+            // the position and tree of the last search, kept for reuse on the next `go`
+            let mut previous: Option<(Board, mcts::Root)> = None;
+            let cache = cache::NNCache::new(DEFAULT_NN_CACHE_SIZE);
+            // the evaluator kept across searches, and the options it was made for
+            let mut evaluator: Option<Arc<dyn Evaluator>> = None;
+            let mut evaluator_kind = None;
             loop {
-                #[allow(unused)]
-                use std::process::{Command, Stdio};
-
-                #[cfg(feature = "use-external-eval")]
-                let mut child = Command::new(ENGINE)
-                    .stdin(Stdio::piped())
-                    .stdout(Stdio::piped())
-                    .spawn()
-                    .expect("failed to execute child");
-
-                let recv: (Game, Option<UciTimeControl>) = rx.recv().unwrap();
-                should_stop.store(false, Ordering::Relaxed);
-                let board = recv.0;
-                let time_control = recv.1;
-                let mut root = mcts::Root::new(board.current_position(), &model);
-                let now = Instant::now();
-                let target = match time_control {
-                    Some(time) => match time {
-                        UciTimeControl::MoveTime(duration) => duration.to_std().unwrap(),
-                        UciTimeControl::TimeLeft {
-                            white_time,
-                            black_time,
-                            white_increment,
-                            ..
-                        } => {
-                            let time_left = match board.side_to_move() {
-                                Color::White => {
-                                    white_time.unwrap().to_std().unwrap()
-                                }
-                                Color::Black => {
-                                    black_time.unwrap().to_std().unwrap()
-                                }
-                            };
-
-                            /*let moves = board.actions().len() as f32 / 2.0;
-                            let moves_left = if moves < 60.0 {
-                                ((-2.0 / 3.0) * moves + 50.0) as u32
-                            } else {
-                                ((1.0 / 10.0) * (moves - 60.0) + 10.0) as u32
-                            };*/
-                            (time_left / 40).min(Duration::from_secs(60))
-                                + white_increment.unwrap_or(vampirc_uci::Duration::milliseconds(0)).to_std().unwrap()
+                let request = match rx.recv() {
+                    Ok(Request::Search(request)) => *request,
+                    Ok(Request::NewGame(options, done)) => {
+                        previous = None;
+                        if network.switch(&weights_file(&options)) {
+                            cache.clear();
+                            network.report();
                         }
-                        _ => Duration::from_millis(60000),
-                    },
-                    None => Duration::from_millis(60000),
+                        let _ = done.send(());
+                        continue;
+                    }
+                    // the UCI loop has quit
+                    Err(_) => break,
+                };
+                let history = request.history;
+                let board = history.board();
+                let time_control = request.time_control;
+                let search_control = request.search_control;
+                let options = request.options;
+                let should_stop = request.should_stop;
+                let pondering = request.pondering;
+                let batch_size = options.batch_size();
+
+                let kind = Some((
+                    options.evaluator().to_string(),
+                    options.external_engine().to_string(),
+                    options.external_limits(),
+                    options.external_blend(),
+                ));
+                let mut switched = network.switch(&weights_file(&options));
+                if switched {
+                    network.report();
+                }
+                if kind != evaluator_kind {
+                    evaluator_kind = kind;
+                    switched = true;
+                }
+                if switched {
+                    // evaluations and trees of the old evaluator are of no use
+                    cache.clear();
+                    previous = None;
+                    evaluator = None;
+                }
+
+                let evaluator = match (&evaluator, options.evaluator(), &network.model) {
+                    (Some(evaluator), _, _) => evaluator.clone(),
+                    (None, "Mock", _) => evaluator.insert(Arc::new(MockEvaluator)).clone(),
+                    (None, "External", Some(model)) => evaluator
+                        .insert(Arc::new(ExternalEvaluator::new(
+                            options.external_engine(),
+                            options.external_limits(),
+                            options.external_blend(),
+                            model.clone(),
+                        )))
+                        .clone(),
+                    (None, _, Some(model)) => evaluator.insert(model.clone()).clone(),
+                    (None, _, None) => {
+                        println!(
+                            "info string no network loaded: {}",
+                            network.error.as_deref().unwrap_or("unknown error")
+                        );
+                        println!("bestmove 0000");
+                        continue;
+                    }
                 };
+                let evaluator = &*evaluator;
+
+                // the root of a finished game has no move to search or print
+                if board.status() != BoardStatus::Ongoing {
+                    println!("info string no legal moves in this position");
+                    println!("bestmove 0000");
+                    continue;
+                }
 
-                let mut rollouts = 0;
-                tch::no_grad(|| loop {
-                    // make sure that a sensical move is chosen when time is low
-                    if now.elapsed() >= target {
-                        break;
+                #[cfg(feature = "torch")]
+                if options.torch_threads() > 0 {
+                    tch::set_num_threads(options.torch_threads() as i32);
+                }
+
+                cache.resize(options.nn_cache_size());
+                cache.reset_stats();
+                let mut root = match previous
+                    .take()
+                    .filter(|(_, root)| root.uses_transpositions() == options.transpositions())
+                    .and_then(|(previous_board, previous_root)| {
+                        previous_root.promote(previous_board, board, MAX_REUSE_PLIES)
+                    }) {
+                    Some(root) => {
+                        println!(
+                            "info string reusing tree with {} visits",
+                            root.root_node().get_n()
+                        );
+                        root
                     }
+                    None => mcts::Root::new(&history, evaluator, options.transpositions()),
+                };
+                root.set_cpuct(options.cpuct());
+                root.set_memory_limit(options.hash() * 1024 * 1024);
 
-                    root.parallel_rollouts(board.current_position(), &model, 8, {
-                        cfg_if::cfg_if! {
-                            if #[cfg(feature = "use-external-eval")] {
-                                Some(&mut child)
-                            } else {
-                                None
-                            }
-                        }
-                    });
-                    if should_stop.load(Ordering::Relaxed) {
-                        should_stop.store(false, Ordering::Relaxed);
-                        break;
+                // `go searchmoves`, ignoring moves that are not legal here
+                let search_moves = search_control
+                    .as_ref()
+                    .map(|control| {
+                        control
+                            .search_moves
+                            .iter()
+                            .map(to_chess_move)
+                            .filter(|mov| board.legal(*mov))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                root.set_search_moves(search_moves);
+
+                let max_nodes = search_control.as_ref().and_then(|control| control.nodes);
+                let max_depth = search_control.as_ref().and_then(|control| control.depth);
+                let mate = search_control.as_ref().and_then(|control| control.mate);
+                let has_limit = max_nodes.is_some() || max_depth.is_some() || mate.is_some();
+
+                let now = Instant::now();
+                // the time budget starts counting at `ponderhit` when pondering
+                let mut clock = now;
+                let mut is_pondering = pondering.load(Ordering::Relaxed);
+                let infinite = matches!(time_control, Some(UciTimeControl::Infinite));
+                // `None` searches until `stop` or one of the other limits is hit
+                let move_overhead = Duration::from_millis(options.move_overhead());
+                let budget = match &time_control {
+                    Some(time_control) => {
+                        TimeBudget::new(time_control, board.side_to_move(), move_overhead)
+                    }
+                    None if has_limit => None,
+                    None => Some(TimeBudget::fixed(Duration::from_millis(60000))),
+                };
+                // when the best move last changed, to spend more time while it does
+                let mut best_move = None;
+                let mut best_since = now;
+
+                let rollouts = AtomicUsize::new(0);
+                let finished = AtomicBool::new(false);
+                thread::scope(|s| {
+                    for _ in 1..options.threads() {
+                        s.spawn(|| {
+                            helper_search(
+                                &root, &history, evaluator, &cache, batch_size, &rollouts, &finished,
+                            )
+                        });
                     }
-                    let edge = root.root_node();
-                    let edge = edge.borrow();
-                    rollouts += 8;
-                    let q = edge.get_q() * 2.0 - 1.0;
-                    let score = -(q.signum() * (1.0 - q.abs()).ln() / (1.2f32).ln()) * 100.0 / 2.0;
-
-                    let pv = {
-                        let mut pv = vec![];
-                        let mut current_node = root.root_node();
-                        let mut pv_game = board.clone();
-                        loop {
-                            let node = current_node.borrow();
-                            let edge = node.max_n_select(&pv_game, true);
-                            if let Some(edge) = edge {
-                                let edge = edge.borrow();
-                                let best_move = edge.mov;
-                                let best_move = if let Some(_) = best_move.get_promotion() {
-                                    ChessMove::new(
-                                        best_move.get_source(),
-                                        best_move.get_dest(),
-                                        Some(Piece::Queen),
-                                    )
-                                } else {
-                                    best_move
-                                };
-                                pv.push(best_move);
-                                pv_game.make_move(best_move);
-                                drop(node);
-                                if let Some(child) = &edge.child {
-                                    current_node = child.clone();
-                                } else {
-                                    break;
-                                }
-                            } else {
+
+                    loop {
+                        if is_pondering && !pondering.load(Ordering::Relaxed) {
+                            is_pondering = false;
+                            clock = Instant::now();
+                        }
+
+                        // a full tree ends the search, unless the GUI will end it with `stop`
+                        if root.is_full() {
+                            if !is_pondering && !infinite {
+                                break;
+                            }
+                            if should_stop.load(Ordering::Relaxed) {
                                 break;
                             }
+                            thread::sleep(Duration::from_millis(10));
+                            continue;
                         }
 
-                        pv.iter()
-                            .map(|mov| format!("{}", mov))
-                            .collect::<Vec<_>>()
-                            .join(" ")
-                    };
-
-                    println!(
-                        "info currmove {} depth {} score cp {} nodes {} nps {} time {} pv {}",
-                        edge.max_n_select(&board, true).unwrap().borrow().mov,
-                        root.depth,
-                        score as i32,
-                        rollouts,
-                        rollouts as u32 / now.elapsed().as_secs().max(1) as u32,
-                        now.elapsed().as_millis(),
-                        pv
-                    );
-                });
-                let mut pv = vec![];
-                let mut current_node = root.root_node();
-                let mut pv_game = board.clone();
-                loop {
-                    let node = current_node.borrow();
-                    let edge = node.max_n_select(&pv_game, true);
-                    if let Some(edge) = edge {
-                        let edge = edge.borrow();
-                        let best_move = edge.mov;
-                        let best_move = if let Some(_) = best_move.get_promotion() {
-                            ChessMove::new(
-                                best_move.get_source(),
-                                best_move.get_dest(),
-                                Some(Piece::Queen),
-                            )
-                        } else {
-                            best_move
+                        // with a single move to choose from there is nothing to think about
+                        if !is_pondering && budget.is_some() && root.search_edges().count() == 1 {
+                            break;
+                        }
+
+                        // make sure that a sensical move is chosen when time is low
+                        let stable_for = best_since.elapsed();
+                        if !is_pondering
+                            && budget.is_some_and(|budget| budget.is_over(clock.elapsed(), stable_for))
+                        {
+                            break;
+                        }
+
+                        root.parallel_rollouts(&history, evaluator, &cache, batch_size);
+                        if should_stop.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        let root_node = root.root_node();
+                        let current_best = root_node.max_n_select(root.nodes(), root.search_moves()).map(|edge| edge.mov);
+                        if current_best != best_move {
+                            best_move = current_best;
+                            best_since = Instant::now();
+                        }
+                        let rollouts = rollouts.fetch_add(batch_size, Ordering::Relaxed) + batch_size;
+
+                        print_info(&root, options.multipv(), rollouts, now.elapsed());
+
+                        let mate_found = match (mate, root.proven()) {
+                            (Some(moves), mcts::Proven::Win(plies)) => plies.div_ceil(2) <= moves as u32,
+                            _ => false,
                         };
-                        pv.push(best_move);
-                        pv_game.make_move(best_move);
-                        drop(node);
-                        if let Some(child) = &edge.child {
-                            current_node = child.clone();
-                        } else {
+                        // `go depth` is met by the average rollout, not the longest one
+                        let limit_reached = max_nodes.is_some_and(|nodes| rollouts as u64 >= nodes)
+                            || max_depth.is_some_and(|depth| root.depth() >= depth as usize)
+                            || mate_found;
+                        // a ponder search may only end with `stop`
+                        if limit_reached && !is_pondering {
                             break;
                         }
-                    } else {
-                        break;
+
+                        // stop once the rollouts left cannot change the move played
+                        let smart_pruning = !is_pondering && options.smart_pruning();
+                        if let Some(budget) = budget.filter(|_| smart_pruning) {
+                            let nps = rollouts as f32 / now.elapsed().as_secs_f32();
+                            let time_left = budget.hard.saturating_sub(clock.elapsed());
+                            let mut remaining = time_left.as_secs_f32() * nps;
+                            if let Some(nodes) = max_nodes {
+                                remaining = remaining.min(nodes.saturating_sub(rollouts as u64) as f32);
+                            }
+                            if root.is_decided(remaining) {
+                                break;
+                            }
+                        }
                     }
-                }
+                    finished.store(true, Ordering::Relaxed);
+                });
 
+                let rollouts = rollouts.into_inner();
+                let pv = root.principal_variation(root.root_index(), root.search_moves());
                 let best_move = pv[0];
-                let pv = pv
-                    .iter()
-                    .map(|mov| format!("{}", mov))
-                    .collect::<Vec<_>>()
-                    .join(" ");
+                let ponder_move = pv.get(1).copied();
+                print_info(&root, options.multipv(), rollouts, now.elapsed());
+
                 println!(
-                    "info currmove {} depth {} nodes {} time {} pv {}",
-                    best_move,
-                    root.depth,
-                    rollouts,
-                    now.elapsed().as_millis(),
-                    pv
+                    "info string nncache hits {} misses {} hitrate {:.1}%",
+                    cache.hits(),
+                    cache.misses(),
+                    cache.hit_rate() * 100.0
                 );
+                match ponder_move {
+                    Some(ponder_move) => println!("bestmove {} ponder {}", best_move, ponder_move),
+                    None => println!("bestmove {}", best_move),
+                }
 
-                println!("bestmove {}", best_move);
-
-                #[cfg(feature = "use-external-eval")]
-                {
-                    let _ = child.kill();
-                    let _ = child.wait();
+                // a ponder search ended by `stop` means the opponent played
+                // something else, so its tree is of no further use
+                if !is_pondering {
+                    previous = Some((board, root));
                 }
             }
         });
     }
 
     for line in io::stdin().lock().lines() {
-        let msg: UciMessage = parse_one(&line.unwrap());
+        let line = line.unwrap();
+        let msg: UciMessage = parse_one(&line);
         match msg {
             UciMessage::Uci => {
                 println!("id name DivineNN");
+                for option in options::OPTIONS {
+                    println!("{}", option.declaration());
+                }
                 println!("uciok")
             }
+            UciMessage::SetOption { name, value } => {
+                if let Err(err) = options.set(&name, value.as_deref()) {
+                    println!("info string {}", err);
+                }
+            }
             UciMessage::Position {
                 startpos,
                 moves,
                 fen,
             } => {
                 if startpos {
-                    board = Game::new();
+                    history = History::new(Board::default(), 0);
                 } else if let Some(fen) = fen {
-                    board = Game::from_str(&fen.0).unwrap();
+                    history = History::from_fen(&fen.0).unwrap();
                 }
 
                 for mov in moves {
-                    let from = mov.from;
-                    let to = mov.to;
-
-                    let from = Square::make_square(
-                        match from.rank {
-                            1 => Rank::First,
-                            2 => Rank::Second,
-                            3 => Rank::Third,
-                            4 => Rank::Fourth,
-                            5 => Rank::Fifth,
-                            6 => Rank::Sixth,
-                            7 => Rank::Seventh,
-                            8 => Rank::Eighth,
-                            _ => unreachable!(),
-                        },
-                        match from.file {
-                            'a' => File::A,
-                            'b' => File::B,
-                            'c' => File::C,
-                            'd' => File::D,
-                            'e' => File::E,
-                            'f' => File::F,
-                            'g' => File::G,
-                            'h' => File::H,
-                            _ => unreachable!(),
-                        },
-                    );
-
-                    let to = Square::make_square(
-                        match to.rank {
-                            1 => Rank::First,
-                            2 => Rank::Second,
-                            3 => Rank::Third,
-                            4 => Rank::Fourth,
-                            5 => Rank::Fifth,
-                            6 => Rank::Sixth,
-                            7 => Rank::Seventh,
-                            8 => Rank::Eighth,
-                            _ => unreachable!(),
-                        },
-                        match to.file {
-                            'a' => File::A,
-                            'b' => File::B,
-                            'c' => File::C,
-                            'd' => File::D,
-                            'e' => File::E,
-                            'f' => File::F,
-                            'g' => File::G,
-                            'h' => File::H,
-                            _ => unreachable!(),
-                        },
-                    );
-                    let mov = ChessMove::new(
-                        from,
-                        to,
-                        mov.promotion.map(|piece| match piece {
-                            UciPiece::Pawn => Piece::Pawn,
-                            UciPiece::Knight => Piece::Knight,
-                            UciPiece::Bishop => Piece::Bishop,
-                            UciPiece::Rook => Piece::Rook,
-                            UciPiece::Queen => Piece::Queen,
-                            UciPiece::King => Piece::King,
-                        }),
-                    );
-                    board.make_move(mov);
+                    history.make_move(to_chess_move(&mov));
                 }
             }
-            UciMessage::Go { time_control, .. } => {
-                tx.send((board.clone(), time_control)).unwrap();
+            UciMessage::Go {
+                time_control,
+                search_control,
+            } => {
+                // vampirc-uci drops `ponder` when clock times follow it
+                let ponder = matches!(time_control, Some(UciTimeControl::Ponder))
+                    || line
+                        .split_whitespace()
+                        .any(|token| token.eq_ignore_ascii_case("ponder"));
+                should_stop = Arc::new(AtomicBool::new(false));
+                pondering = Arc::new(AtomicBool::new(ponder));
+                tx.send(Request::Search(Box::new(SearchRequest {
+                    history: history.clone(),
+                    time_control,
+                    search_control,
+                    pondering: pondering.clone(),
+                    options: options.clone(),
+                    should_stop: should_stop.clone(),
+                })))
+                .unwrap();
+            }
+            UciMessage::UciNewGame => {
+                // wait for the network to load so that `isready` answers truthfully
+                let (done_tx, done_rx) = mpsc::channel();
+                tx.send(Request::NewGame(options.clone(), done_tx)).unwrap();
+                let _ = done_rx.recv();
             }
             UciMessage::IsReady => println!("readyok"),
+            // `bench [nodes]`: measure the search itself, without a network
+            UciMessage::Unknown(..) if line.trim().starts_with("bench") => {
+                let nodes = line
+                    .split_whitespace()
+                    .nth(1)
+                    .and_then(|nodes| nodes.parse().ok())
+                    .unwrap_or(BENCH_NODES);
+                bench(nodes, options.batch_size());
+            }
+            // `external`: a second opinion on the current position from the external engine
+            UciMessage::Unknown(..) if line.trim() == "external" => {
+                let timeout = Duration::from_millis(EXTERNAL_TIMEOUT);
+                let analysis = uci_engine::UciEngine::start(options.external_engine(), timeout)
+                    .and_then(|mut engine| {
+                        engine.analyse(&history.board(), options.external_limits())
+                    });
+                match analysis {
+                    Ok(analysis) => println!(
+                        "info string external engine score {} bestmove {}",
+                        analysis.score,
+                        analysis.best_move.map_or("0000".to_string(), |mov| mov.to_string())
+                    ),
+                    Err(err) => println!("info string external engine: {}", err),
+                }
+            }
             UciMessage::Quit => break,
             UciMessage::Stop => {
                 should_stop.store(true, Ordering::Relaxed);
             }
+            UciMessage::PonderHit => {
+                pondering.store(false, Ordering::Relaxed);
+            }
             _ => {}
         }
     }
@@ -3,45 +3,204 @@
 // 8/5p1p/k3r3/p1Q5/3P4/8/P4nP1/1K6 w - - 1 52
 
 use super::*;
+use crate::arena::Arena;
+use crate::cache::{self, Evaluation, NNCache};
+use crate::eval::Evaluator;
+use crate::history::is_insufficient_material;
+
+use std::collections::{HashMap, HashSet};
+use std::mem::size_of;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+/// Position of a node in the tree's arena.
+pub type NodeIndex = u32;
+/// Storage for all nodes of one search tree. Edges refer to their children by index.
+pub type Nodes = Arena<Node>;
+
+// what an edge's child index holds before the edge is expanded
+const NO_CHILD: NodeIndex = NodeIndex::MAX;
+
+/// Nodes that can be reached through more than one move order, keyed by
+/// `Board::get_hash`.
+type TranspositionTable = Mutex<HashMap<u64, NodeIndex>>;
+
+// rough cost of one transposition table entry, including the hash map's own bookkeeping
+const TRANSPOSITION_ENTRY: usize = 32;
+
+/// An `f32` that can be updated from several search threads at once.
+#[derive(Debug, Default)]
+pub struct AtomicF32(AtomicU32);
+
+impl AtomicF32 {
+    pub fn new(value: f32) -> Self {
+        Self(AtomicU32::new(value.to_bits()))
+    }
+
+    pub fn load(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn fetch_add(&self, value: f32) -> f32 {
+        let previous = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f32::from_bits(bits) + value).to_bits())
+            })
+            .unwrap();
+
+        f32::from_bits(previous)
+    }
+}
+
+/// A game-theoretic result established by the search, from the point of view
+/// of the side to move at the node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Proven {
+    Unknown,
+    /// The side to move mates in this many plies.
+    Win(u32),
+    /// The side to move gets mated in this many plies.
+    Loss(u32),
+    Draw,
+}
+
+impl Proven {
+    fn to_bits(self) -> u32 {
+        match self {
+            Proven::Unknown => 0,
+            Proven::Draw => 1,
+            Proven::Win(plies) => plies << 2 | 2,
+            Proven::Loss(plies) => plies << 2 | 3,
+        }
+    }
 
-use std::cell::RefCell;
-use std::process::Child;
-use std::rc::Rc;
+    fn from_bits(bits: u32) -> Self {
+        match bits & 3 {
+            0 => Proven::Unknown,
+            1 => Proven::Draw,
+            2 => Proven::Win(bits >> 2),
+            _ => Proven::Loss(bits >> 2),
+        }
+    }
 
-pub fn calculate_uct(edge: &Edge, n_p: f32, root: bool) -> f32 {
-    let q = edge.get_q();
+    /// The same result seen from the position one move earlier.
+    pub fn flip(self) -> Self {
+        match self {
+            Proven::Unknown => Proven::Unknown,
+            Proven::Win(plies) => Proven::Loss(plies + 1),
+            Proven::Loss(plies) => Proven::Win(plies + 1),
+            Proven::Draw => Proven::Draw,
+        }
+    }
+
+    /// The exact q of a proven result.
+    pub fn q(self) -> Option<f32> {
+        match self {
+            Proven::Unknown => None,
+            Proven::Win(_) => Some(1.0),
+            Proven::Loss(_) => Some(0.0),
+            Proven::Draw => Some(0.5),
+        }
+    }
+}
+
+pub fn calculate_uct(nodes: &Nodes, edge: &Edge, n_p: f32, _root: bool, cpuct: f32) -> f32 {
+    let q = edge.get_q(nodes);
     let n_c = edge.get_n();
     let p = edge.p;
 
     /*let init = 1.745;
     let factor = 3.894;
 
-    let c = if root {
+    let c = if _root {
         init + factor * ((n_c + 38739.0) / 38739.00).ln()
     } else {
         3.1
     };*/
-    let uct = q + p * 1.25 * n_p.sqrt() / (1.0 + n_c);
+    q + p * cpuct * n_p.sqrt() / (1.0 + n_c)
+}
 
-    uct
+fn is_allowed(mov: ChessMove, moves: &[ChessMove]) -> bool {
+    moves.is_empty() || moves.contains(&mov)
+}
+
+/// The result of a position whose `moves` legal moves lead through `edges`,
+/// as `Node::update_proven` settles it.
+fn settle<'a>(nodes: &Nodes, edges: impl Iterator<Item = &'a Edge>, moves: usize) -> Proven {
+    let mut quickest_win: Option<u32> = None;
+    let mut slowest_loss: Option<u32> = Some(0);
+    let mut draw = false;
+    let mut materialized = 0;
+    for edge in edges {
+        materialized += 1;
+        match edge.proven(nodes) {
+            Proven::Loss(plies) => quickest_win = Some(quickest_win.map_or(plies, |q| q.min(plies))),
+            Proven::Win(plies) => slowest_loss = slowest_loss.map(|s| s.max(plies)),
+            Proven::Draw => draw = true,
+            Proven::Unknown => slowest_loss = None,
+        }
+    }
+    // moves without an edge have not been searched at all
+    if materialized < moves {
+        slowest_loss = None;
+    }
+
+    match (quickest_win, slowest_loss) {
+        (Some(plies), _) => Proven::Win(plies + 1),
+        (None, Some(_)) if draw => Proven::Draw,
+        (None, Some(plies)) => Proven::Loss(plies + 1),
+        (None, None) => Proven::Unknown,
+    }
 }
 
 #[allow(unused)]
-pub fn calculate_uct_no_cpuct(edge: &Edge, n_p: f32) -> f32 {
-    let q = edge.get_q();
+pub fn calculate_uct_no_cpuct(nodes: &Nodes, edge: &Edge, n_p: f32) -> f32 {
+    let q = edge.get_q(nodes);
     let n_c = edge.get_n();
     let p = edge.p;
 
-    let uct = q + p * 0.0 * n_p.sqrt() / (1.0 + n_c);
+    q + p * 0.0 * n_p.sqrt() / (1.0 + n_c)
+}
+
+// edges are materialized this many at a time, in prior order
+const EDGE_CHUNK: usize = 8;
 
-    uct
+/// A run of consecutive edges of one node, linked to the run after it.
+#[derive(Debug)]
+struct EdgeChunk {
+    edges: Box<[Edge]>,
+    next: OnceLock<Box<EdgeChunk>>,
 }
 
-#[derive(Clone, Debug)]
+impl EdgeChunk {
+    /// The chunk for the moves starting with `priors[0]`.
+    fn new(priors: &[(ChessMove, f32)]) -> Self {
+        Self {
+            edges: priors
+                .iter()
+                .take(EDGE_CHUNK)
+                .map(|(mov, p)| Edge::new(*mov, *p))
+                .collect(),
+            next: OnceLock::new(),
+        }
+    }
+
+    fn memory(&self) -> usize {
+        size_of::<EdgeChunk>() + self.edges.len() * size_of::<Edge>()
+    }
+}
+
+#[derive(Debug)]
 pub struct Node {
-    pub n: f32,
-    sum_q: f32,
-    edges: Vec<Rc<RefCell<Edge>>>,
+    n: AtomicU32,
+    sum_q: AtomicF32,
+    // every legal move with its normalized prior, most likely first
+    priors: Box<[(ChessMove, f32)]>,
+    // the statistics of the first few moves in `priors`. Unvisited moves are
+    // selected in prior order, so they only get an edge once the search reaches them
+    edges: OnceLock<Box<EdgeChunk>>,
+    proven: AtomicU32,
 }
 
 impl Node {
@@ -50,9 +209,9 @@ impl Node {
         probabilities: &mut [(ChessMove, f32)],
     ) -> Self {
         Self {
-            n: 1.0,
-            sum_q: new_q,
-            edges: {
+            n: AtomicU32::new(1),
+            sum_q: AtomicF32::new(new_q),
+            priors: {
                 let mut probabilities = probabilities.to_vec();
                 probabilities.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
                 let mut total = 0.0;
@@ -60,88 +219,228 @@ impl Node {
                     total += probability.1;
                 }
 
-                let edges = probabilities
+                probabilities
                     .into_iter()
-                    .map(|p| Rc::new(RefCell::new(Edge::new(p.0, p.1 / total))))
-                    .collect::<Vec<_>>();
-
-                edges
+                    .map(|p| (p.0, p.1 / total))
+                    .collect()
             },
+            edges: OnceLock::new(),
+            proven: AtomicU32::new(Proven::Unknown.to_bits()),
+        }
+    }
+
+    /// A position where the game is over: checkmate, stalemate or a draw by rule.
+    pub fn terminal(result: Proven) -> Self {
+        Self {
+            n: AtomicU32::new(1),
+            sum_q: AtomicF32::new(result.q().unwrap()),
+            priors: Box::new([]),
+            edges: OnceLock::new(),
+            proven: AtomicU32::new(result.to_bits()),
+        }
+    }
+
+    /// A copy of this node's statistics with none of its edges expanded.
+    fn detached(&self) -> Self {
+        let node = Self {
+            n: AtomicU32::new(self.n.load(Ordering::Relaxed)),
+            sum_q: AtomicF32::new(self.sum_q.load()),
+            priors: self.priors.clone(),
+            edges: OnceLock::new(),
+            proven: AtomicU32::new(self.proven.load(Ordering::Relaxed)),
+        };
+        for (i, edge) in self.edges().enumerate() {
+            let (copy, _) = node.materialize(i);
+            copy.n.store(edge.n.load(Ordering::Relaxed), Ordering::Relaxed);
+            copy.sum_q.fetch_add(edge.sum_q.load());
+        }
+
+        node
+    }
+
+    pub fn get_n(&self) -> f32 {
+        self.n.load(Ordering::Relaxed) as f32
+    }
+
+    /// Heap memory held by this node's priors and edges. The node itself lives in the arena.
+    pub fn memory(&self) -> usize {
+        let chunks = std::iter::successors(self.edges.get(), |chunk| chunk.next.get());
+        self.priors.len() * size_of::<(ChessMove, f32)>() + chunks.map(|chunk| chunk.memory()).sum::<usize>()
+    }
+
+    /// The edges materialized so far, in prior order.
+    pub fn edges(&self) -> impl Iterator<Item = &Edge> {
+        std::iter::successors(self.edges.get(), |chunk| chunk.next.get())
+            .flat_map(|chunk| chunk.edges.iter())
+    }
+
+    /// The edge for the move at `index` in prior order, which must have been materialized.
+    pub fn edge(&self, index: usize) -> &Edge {
+        let mut chunk = self.edges.get();
+        for _ in 0..index / EDGE_CHUNK {
+            chunk = chunk.and_then(|chunk| chunk.next.get());
+        }
+        chunk
+            .map(|chunk| &chunk.edges[index % EDGE_CHUNK])
+            .expect("edge has not been materialized")
+    }
+
+    /// The edge for the move at `index` in prior order, creating it and the
+    /// ones before it if needed. Also returns the bytes this allocated.
+    pub fn materialize(&self, index: usize) -> (&Edge, usize) {
+        let mut allocated = 0;
+        let mut chunk = &self.edges;
+        let mut start = 0;
+        loop {
+            let current = chunk.get_or_init(|| {
+                let new = EdgeChunk::new(&self.priors[start..]);
+                allocated += new.memory();
+                Box::new(new)
+            });
+            if index < start + EDGE_CHUNK {
+                return (&current.edges[index - start], allocated);
+            }
+
+            chunk = &current.next;
+            start += EDGE_CHUNK;
+        }
+    }
+
+    /// Materialize the edges of every legal move, returning the bytes this allocated.
+    pub fn materialize_all(&self) -> usize {
+        match self.priors.len() {
+            0 => 0,
+            len => self.materialize(len - 1).1,
         }
     }
 
     pub fn get_q(&self) -> f32 {
-        self.sum_q / self.n
+        self.sum_q.load() / self.get_n()
     }
 
-    pub fn uct_select(&self, root: bool) -> Option<Rc<RefCell<Edge>>> {
+    /// Record one visit backed up through this node.
+    pub fn update(&self, q: f32) {
+        self.n.fetch_add(1, Ordering::Relaxed);
+        self.sum_q.fetch_add(q);
+    }
+
+    /// Pick the edge to explore. A non-empty `moves` restricts the choice to those moves.
+    pub fn uct_select(&self, nodes: &Nodes, root: bool, moves: &[ChessMove], cpuct: f32) -> Option<usize> {
         let mut max_uct = -1000.0;
         let mut max_edge = None;
+        let n = self.get_n();
+
+        let mut materialized = 0;
+        for (i, edge) in self.edges().enumerate() {
+            materialized += 1;
+            if !is_allowed(edge.mov, moves) {
+                continue;
+            }
 
-        for edge in self.edges.iter() {
-            let uct = calculate_uct(&edge.borrow(), self.n, root);
+            // moves proven to lose are only picked when nothing else is left
+            let uct = if let Proven::Win(_) = edge.proven(nodes) {
+                -999.0
+            } else {
+                calculate_uct(nodes, edge, n, root, cpuct)
+            };
             if max_uct < uct {
                 max_uct = uct;
-                max_edge = Some(edge.clone());
+                max_edge = Some(i);
+            }
+        }
+
+        // the moves without an edge are unvisited and sorted by prior, so only
+        // the first allowed one can beat the edges above
+        let unvisited = self
+            .priors
+            .iter()
+            .enumerate()
+            .skip(materialized)
+            .find(|(_, (mov, _))| is_allowed(*mov, moves));
+        if let Some((i, (mov, p))) = unvisited {
+            if max_uct < calculate_uct(nodes, &Edge::new(*mov, *p), n, root, cpuct) {
+                max_edge = Some(i);
             }
         }
 
         max_edge
     }
 
-    pub fn max_n_select(&self, game: &Game, detect_draw: bool) -> Option<Rc<RefCell<Edge>>> {
+    /// Pick the move to play. A non-empty `moves` restricts the choice to those moves.
+    pub fn max_n_select(&self, nodes: &Nodes, moves: &[ChessMove]) -> Option<&Edge> {
+        // play the quickest proven mate if there is one
+        let quickest_mate = self
+            .edges()
+            .filter(|edge| is_allowed(edge.mov, moves))
+            .filter_map(|edge| match edge.proven(nodes) {
+                Proven::Loss(plies) => Some((plies, edge)),
+                _ => None,
+            })
+            .min_by_key(|(plies, _)| *plies);
+        if let Some((_, edge)) = quickest_mate {
+            return Some(edge);
+        }
+
         let mut max_n = -1.0;
         let mut max_edge = None;
-        let score = ((self.get_q() - 0.5) * 15.0 * 100.0) as i32;
-
-        'outer: for edge in self.edges.iter() {
-            let unlocked_edge = edge.borrow();
-            if score >= 0 && detect_draw {
-                // try the move
-                let mut game = game.clone();
-                game.make_move(unlocked_edge.mov);
-                if game.can_declare_draw() {
-                    println!("Draw possible");
-                    continue;
-                } else {
-                    let movegen = MoveGen::new_legal(&game.current_position());
-                    for mov in movegen {
-                        let mut game = game.clone();
-                        game.make_move(mov);
-                        if game.can_declare_draw() {
-                            continue 'outer;
-                        }
-                    }
-                }
-            }
 
-           // let value = calculate_uct_no_cpuct(&edge.borrow(), self.n);
-            //let value = unlocked_edge.get_q();
-            let value = unlocked_edge.get_n();
+        for edge in self.edges().filter(|edge| is_allowed(edge.mov, moves)) {
+           // let value = calculate_uct_no_cpuct(nodes, edge, self.get_n());
+            //let value = edge.get_q(nodes);
+            let mut value = edge.get_n();
+            // only fall back to a proven loss when every move loses
+            if let Proven::Win(plies) = edge.proven(nodes) {
+                value = -1.0 + plies as f32 / 1000.0;
+            }
             if max_n < value {
                 max_n = value;
-                max_edge = Some(edge.clone());
+                max_edge = Some(edge);
             }
         }
 
-        if max_edge.is_none() && detect_draw {
-            max_edge = self.max_n_select(game, false);
-        }
-
         max_edge
     }
 
     pub fn is_terminal(&self) -> bool {
-        self.edges.len() == 0
+        self.priors.is_empty()
+    }
+
+    pub fn proven(&self) -> Proven {
+        Proven::from_bits(self.proven.load(Ordering::Relaxed))
+    }
+
+    /// Settle this node from its children if possible: one child lost for the
+    /// opponent makes it a win, and once every child is proven the best of them
+    /// decides. Returns true if the node became proven.
+    pub fn update_proven(&self, nodes: &Nodes) -> bool {
+        if self.proven() != Proven::Unknown || self.priors.is_empty() {
+            return false;
+        }
+
+        let result = settle(nodes, self.edges(), self.priors.len());
+        if result == Proven::Unknown {
+            return false;
+        }
+
+        self.proven.store(result.to_bits(), Ordering::Relaxed);
+        true
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub struct Edge {
     pub mov: ChessMove,
     p: f32,
-    pub child: Option<Rc<RefCell<Node>>>,
-    virtual_losses: f32,
+    // index of the child node, or `NO_CHILD`
+    child: AtomicU32,
+    // visits that went through this edge; with transpositions the child node
+    // can have more visits than this, coming from other parents
+    n: AtomicU32,
+    // q of those visits for the side to move before the edge. Only read while
+    // the edge has no child, which happens when every visit ended in a draw by
+    // repetition or the fifty-move rule
+    sum_q: AtomicF32,
+    virtual_losses: AtomicU32,
 }
 
 impl Edge {
@@ -149,305 +448,673 @@ impl Edge {
         Self {
             mov,
             p: probability,
-            child: None,
-            virtual_losses: 0.0,
+            child: AtomicU32::new(NO_CHILD),
+            n: AtomicU32::new(0),
+            sum_q: AtomicF32::new(0.0),
+            virtual_losses: AtomicU32::new(0),
         }
     }
 
-    pub fn has_child(&self) -> bool {
-        self.child.is_some()
+    pub fn child_index(&self) -> Option<NodeIndex> {
+        // pairs with the release in `link`, so that the node is visible
+        Some(self.child.load(Ordering::Acquire)).filter(|index| *index != NO_CHILD)
+    }
+
+    pub fn child<'a>(&self, nodes: &'a Nodes) -> Option<&'a Node> {
+        self.child_index().map(|index| nodes.get(index))
+    }
+
+    fn get_virtual_losses(&self) -> f32 {
+        self.virtual_losses.load(Ordering::Relaxed) as f32
     }
 
     pub fn get_n(&self) -> f32 {
-        if let Some(child) = &self.child {
-            child.borrow().n + self.virtual_losses
-        } else {
-            self.virtual_losses
-        }
+        self.get_visits() + self.get_virtual_losses()
     }
 
-    pub fn get_q(&self) -> f32 {
-        if let Some(child) = &self.child {
-            let child = child.borrow();
-            1.0 - ((child.sum_q + self.virtual_losses) / (child.n + self.virtual_losses))
+    /// Visits backed up through this edge, without the rollouts still in flight.
+    pub fn get_visits(&self) -> f32 {
+        self.n.load(Ordering::Relaxed) as f32
+    }
+
+    /// The proven result of the child position, from the child's side to move.
+    pub fn proven(&self, nodes: &Nodes) -> Proven {
+        self.child(nodes).map_or(Proven::Unknown, |child| child.proven())
+    }
+
+    /// q for the side to move before this edge, counting rollouts in flight as losses.
+    pub fn get_q(&self, nodes: &Nodes) -> f32 {
+        self.q_with(nodes, self.get_virtual_losses())
+    }
+
+    /// q as `get_q` gives it, but only from the visits backed up so far. For reporting.
+    pub fn get_reported_q(&self, nodes: &Nodes) -> f32 {
+        self.q_with(nodes, 0.0)
+    }
+
+    fn q_with(&self, nodes: &Nodes, virtual_losses: f32) -> f32 {
+        if let Some(child) = self.child(nodes) {
+            if let Some(q) = child.proven().q() {
+                return 1.0 - q;
+            }
+
+            1.0 - ((child.sum_q.load() + virtual_losses) / (child.get_n() + virtual_losses))
+        } else if self.n.load(Ordering::Relaxed) > 0 {
+            self.sum_q.load() / (self.get_visits() + virtual_losses)
         } else {
             0.0
         }
     }
 
-    pub fn expand(
-        &mut self,
-        new_q: f32,
-        move_probabilities: &mut [(ChessMove, f32)],
-    ) -> bool {
-        if self.child.is_none() {
-            self.child = Some(Rc::new(RefCell::new(Node::new(
-                new_q,
-                move_probabilities,
-            ))));
-
-            true
-        } else {
-            false
+    /// Point this edge at `node` unless it already has a child. Returns
+    /// whichever node the edge ends up with, in case it was expanded concurrently.
+    pub fn link(&self, node: NodeIndex) -> NodeIndex {
+        match self
+            .child
+            .compare_exchange(NO_CHILD, node, Ordering::Release, Ordering::Acquire)
+        {
+            Ok(_) => node,
+            Err(child) => child,
         }
     }
 
-    pub fn add_virtual_loss(&mut self) {
-        self.virtual_losses += 1.0;
+    /// Record one visit backed up through this edge, releasing its virtual loss.
+    /// `q` is the visit's result for the side to move before the edge.
+    pub fn update(&self, q: f32) {
+        self.n.fetch_add(1, Ordering::Relaxed);
+        self.sum_q.fetch_add(q);
+        self.virtual_losses.fetch_sub(1, Ordering::Relaxed);
     }
 
-    pub fn clear_virtual_loss(&mut self) {
-        self.virtual_losses = 0.0;
+    pub fn add_virtual_loss(&self) {
+        self.virtual_losses.fetch_add(1, Ordering::Relaxed);
     }
 }
 
-#[derive(Clone, Debug)]
 pub struct Root {
-    root_node: Rc<RefCell<Node>>,
-    pub depth: usize,
-    pub same_paths: usize,
+    nodes: Nodes,
+    root_node: NodeIndex,
+    transpositions: Option<TranspositionTable>,
+    // `go searchmoves`: the root moves the search may look at, or empty for all
+    search_moves: Vec<ChessMove>,
+    // exploration constant used when selecting edges
+    cpuct: f32,
+    // bytes held by edges and transposition table entries, on top of the arena
+    // itself, and the most the search may grow the tree to
+    memory: AtomicUsize,
+    memory_limit: usize,
+    // the longest rollout of this search, and the total length and number of
+    // its rollouts, in plies
+    seldepth: AtomicUsize,
+    depth_sum: AtomicUsize,
+    depth_count: AtomicUsize,
+    same_paths: AtomicUsize,
 }
 
 impl Root {
-    pub fn new(board: Board, network: &tch::CModule) -> Self {
-        let (mut move_probabilities, value) = get_neural_output(board, network);
-        let q = value / 2.0 + 0.5;
-        let node = Node::new(q, &mut move_probabilities);
-
+    fn with_nodes(nodes: Nodes, root_node: NodeIndex, transpositions: Option<TranspositionTable>, memory: usize) -> Self {
         Self {
-            root_node: Rc::new(RefCell::new(node)),
-            same_paths: 0,
-            depth: 0,
+            nodes,
+            root_node,
+            transpositions,
+            search_moves: vec![],
+            cpuct: DEFAULT_CPUCT,
+            memory: AtomicUsize::new(memory),
+            memory_limit: usize::MAX,
+            same_paths: AtomicUsize::new(0),
+            seldepth: AtomicUsize::new(0),
+            depth_sum: AtomicUsize::new(0),
+            depth_count: AtomicUsize::new(0),
         }
     }
 
-    pub fn root_node(&self) -> Rc<RefCell<Node>> {
-        self.root_node.clone()
+    pub fn new<E: Evaluator + ?Sized>(history: &History, evaluator: &E, transpositions: bool) -> Self {
+        let board = history.board();
+        let root_node = match board.status() {
+            BoardStatus::Checkmate => Node::terminal(Proven::Loss(0)),
+            BoardStatus::Stalemate => Node::terminal(Proven::Draw),
+            BoardStatus::Ongoing => {
+                let (mut move_probabilities, value) = evaluator.evaluate(std::slice::from_ref(history)).remove(0);
+                let q = value / 2.0 + 0.5;
+                Node::new(q, &mut move_probabilities)
+            }
+        };
+
+        // the root keeps an edge for every move, so that all of them can be reported
+        root_node.materialize_all();
+        let memory = root_node.memory();
+        let nodes = Nodes::new();
+        let root_node = nodes.push(root_node);
+        let transpositions = transpositions
+            .then(|| Mutex::new(HashMap::from([(board.get_hash(), root_node)])));
+
+        Self::with_nodes(nodes, root_node, transpositions, memory)
     }
 
-    pub fn select_task(
-        root_node: Rc<RefCell<Node>>,
-        board: &mut Board,
-        node_path: &mut Vec<Rc<RefCell<Node>>>,
-        edge_path: &mut Vec<Option<Rc<RefCell<Edge>>>>,
-    ) {
-        let mut c_node = root_node.clone();
-        let mut is_root = true;
-        loop {
-            node_path.push(c_node.clone());
-            let c_edge = c_node.borrow().uct_select(is_root);
-            edge_path.push(c_edge.clone());
+    pub fn uses_transpositions(&self) -> bool {
+        self.transpositions.is_some()
+    }
+
+    /// Reuse the statistics gathered by a previous search. `root_board` is the
+    /// position this tree was built for; if `board` can be reached from it in at
+    /// most `max_plies` already expanded moves, that subtree becomes the new root.
+    /// It is copied into a fresh arena, which frees the rest of the old tree. A
+    /// terminal node is not reused: a draw for lack of material has no moves
+    /// stored, but the game goes on and the new root needs them.
+    pub fn promote(self, root_board: Board, board: Board, max_plies: usize) -> Option<Self> {
+        let old_root = self.find_subtree(self.root_node, root_board, board, max_plies)?;
+        if self.nodes.get(old_root).is_terminal() {
+            return None;
+        }
 
-            if c_edge.is_none() {
-                assert!(c_node.borrow().is_terminal());
-                break;
+        let nodes = Nodes::new();
+        let mut memory = 0;
+        let mut copy = |index: NodeIndex| {
+            let node = self.nodes.get(index).detached();
+            memory += node.memory();
+            nodes.push(node)
+        };
+
+        // old index to new, which also keeps nodes shared through transpositions shared
+        let root_node = copy(old_root);
+        let mut copied = HashMap::from([(old_root, root_node)]);
+        let mut stack = vec![(old_root, root_node)];
+        while let Some((old, new)) = stack.pop() {
+            let old_edges = self.nodes.get(old).edges();
+            for (old_edge, new_edge) in old_edges.zip(nodes.get(new).edges()) {
+                let Some(old_child) = old_edge.child_index() else { continue };
+                let new_child = *copied.entry(old_child).or_insert_with(|| {
+                    let new_child = copy(old_child);
+                    stack.push((old_child, new_child));
+                    new_child
+                });
+                new_edge.link(new_child);
             }
+        }
 
-            let c_edge = c_edge.unwrap();
-            let mut c_edge = c_edge.borrow_mut();
+        memory += nodes.get(root_node).materialize_all();
 
-            c_edge.add_virtual_loss();
-            *board = board.make_move_new(c_edge.mov);
+        let transpositions = self.transpositions.map(|transpositions| {
+            let mut transpositions = transpositions.into_inner().unwrap();
+            transpositions.retain(|_, index| match copied.get(index) {
+                Some(new) => {
+                    *index = *new;
+                    true
+                }
+                None => false,
+            });
+            memory += transpositions.len() * TRANSPOSITION_ENTRY;
+            Mutex::new(transpositions)
+        });
+
+        Some(Self::with_nodes(nodes, root_node, transpositions, memory))
+    }
+
+    fn find_subtree(
+        &self,
+        node: NodeIndex,
+        node_board: Board,
+        board: Board,
+        plies_left: usize,
+    ) -> Option<NodeIndex> {
+        if node_board == board {
+            return Some(node);
+        }
+
+        if plies_left == 0 {
+            return None;
+        }
+
+        self.nodes.get(node).edges().find_map(|edge| {
+            let child = edge.child_index()?;
+            self.find_subtree(child, node_board.make_move_new(edge.mov), board, plies_left - 1)
+        })
+    }
 
-            if !c_edge.has_child() {
-                break;
+    pub fn nodes(&self) -> &Nodes {
+        &self.nodes
+    }
+
+    pub fn root_node(&self) -> &Node {
+        self.nodes.get(self.root_node)
+    }
+
+    pub fn root_index(&self) -> NodeIndex {
+        self.root_node
+    }
+
+    /// Follow the most visited edges from `node` to the edge of the tree. A
+    /// non-empty `moves` restricts the first move to those moves. With
+    /// transpositions an edge can lead back to a node already on the line,
+    /// which ends it, as the game would be drawn by repetition there.
+    pub fn principal_variation(&self, node: NodeIndex, moves: &[ChessMove]) -> Vec<ChessMove> {
+        let mut pv = vec![];
+        let mut visited = HashSet::from([node]);
+        let mut current_node = self.nodes.get(node);
+        let mut moves = moves;
+        while let Some(edge) = current_node.max_n_select(&self.nodes, moves) {
+            pv.push(edge.mov);
+            match edge.child_index() {
+                Some(child) if visited.insert(child) => {
+                    current_node = self.nodes.get(child);
+                    moves = &[];
+                }
+                _ => break,
+            }
+        }
+
+        pv
+    }
+
+    pub fn search_moves(&self) -> &[ChessMove] {
+        &self.search_moves
+    }
+
+    /// The root edges the search is allowed to play.
+    pub fn search_edges(&self) -> impl Iterator<Item = &Edge> {
+        self.root_node()
+            .edges()
+            .filter(|edge| is_allowed(edge.mov, &self.search_moves))
+    }
+
+    /// The proven result of the root. With search moves, only those moves count:
+    /// a mate through any other move is not one the engine may play.
+    pub fn proven(&self) -> Proven {
+        let root_node = self.root_node();
+        if self.search_moves.is_empty() || root_node.is_terminal() {
+            return root_node.proven();
+        }
+
+        let moves = root_node.priors.iter().filter(|(mov, _)| is_allowed(*mov, &self.search_moves)).count();
+        settle(&self.nodes, self.search_edges(), moves)
+    }
+
+    /// The q of the root for reporting. With search moves, only the visits
+    /// through those moves count, as a reused tree holds visits through others.
+    pub fn get_q(&self) -> f32 {
+        let root_node = self.root_node();
+        if self.search_moves.is_empty() {
+            return root_node.get_q();
+        }
+
+        let (sum_q, visits) = self
+            .search_edges()
+            .map(|edge| (edge.get_reported_q(&self.nodes) * edge.get_visits(), edge.get_visits()))
+            .fold((0.0, 0.0), |(sum_q, visits), (q, n)| (sum_q + q, visits + n));
+        if visits > 0.0 {
+            sum_q / visits
+        } else {
+            root_node.get_q()
+        }
+    }
+
+    /// Whether `remaining` more rollouts could not change the move `max_n_select`
+    /// picks, because the root is proven or no runner-up can catch up with the best.
+    pub fn is_decided(&self, remaining: f32) -> bool {
+        if self.proven() != Proven::Unknown {
+            return true;
+        }
+
+        // moves proven to lose are never picked while there are others
+        let mut visits = self
+            .search_edges()
+            .filter(|edge| !matches!(edge.proven(&self.nodes), Proven::Win(_)))
+            .map(|edge| edge.get_n())
+            .collect::<Vec<_>>();
+        visits.sort_by(|a, b| b.partial_cmp(a).unwrap());
+        match visits[..] {
+            [best, second, ..] => best - second > remaining,
+            _ => true,
+        }
+    }
+
+    pub fn set_search_moves(&mut self, moves: Vec<ChessMove>) {
+        self.search_moves = moves;
+    }
+
+    pub fn set_cpuct(&mut self, cpuct: f32) {
+        self.cpuct = cpuct;
+    }
+
+    /// Limit the tree to about `bytes`. Rollouts overshoot it by at most a batch per thread.
+    pub fn set_memory_limit(&mut self, bytes: usize) {
+        self.memory_limit = bytes;
+    }
+
+    /// Bytes held by the tree: the arena's slots, including those not used
+    /// yet, the edges and the transposition table.
+    pub fn memory(&self) -> usize {
+        self.nodes.capacity() * size_of::<OnceLock<Node>>() + self.memory.load(Ordering::Relaxed)
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Whether the tree has grown to its memory limit, after which it must not be searched further.
+    pub fn is_full(&self) -> bool {
+        self.memory() >= self.memory_limit
+    }
+
+    /// How full the tree is, in permille of the memory limit, as UCI `hashfull` wants it.
+    pub fn hashfull(&self) -> usize {
+        match self.memory_limit {
+            0 => 1000,
+            limit => (self.memory() as u128 * 1000 / limit as u128).min(1000) as usize,
+        }
+    }
+
+    /// The average length of this search's rollouts in plies, rounded. A single
+    /// narrow line barely moves it, unlike `seldepth`.
+    pub fn depth(&self) -> usize {
+        let count = self.depth_count.load(Ordering::Relaxed);
+        match count {
+            0 => 0,
+            count => (self.depth_sum.load(Ordering::Relaxed) + count / 2) / count,
+        }
+    }
+
+    /// The length of this search's longest rollout in plies.
+    pub fn seldepth(&self) -> usize {
+        self.seldepth.load(Ordering::Relaxed)
+    }
+
+    fn transposition(&self, board: &Board) -> Option<NodeIndex> {
+        let transpositions = self.transpositions.as_ref()?.lock().unwrap();
+        transpositions.get(&board.get_hash()).copied()
+    }
+
+    /// Attach `node` to `edge` unless the edge has a child already. Returns
+    /// false if another rollout (possibly on another thread) got there first.
+    /// If that is seen before pushing, `node` is dropped. If the race is only
+    /// lost when linking, `node` stays in the arena unreachable. It is still
+    /// counted in `memory` and `node_count`, since the arena holds it until
+    /// the tree is dropped or promoted.
+    fn expand(&self, edge: &Edge, node: Node) -> bool {
+        if edge.child_index().is_some() {
+            return false;
+        }
+
+        let memory = node.memory();
+        let index = self.nodes.push(node);
+        self.memory.fetch_add(memory, Ordering::Relaxed);
+        edge.link(index) == index
+    }
+
+    pub fn select_task<'a>(&'a self, job: &mut Job<'a>) {
+        let mut c_node = self.root_node();
+        let mut is_root = true;
+        loop {
+            let moves: &[ChessMove] = if is_root { &self.search_moves } else { &[] };
+            let c_edge = c_node.uct_select(&self.nodes, is_root, moves, self.cpuct);
+            job.edge_path.push(c_edge);
+
+            let c_edge = match c_edge {
+                Some(idx) => {
+                    let (edge, allocated) = c_node.materialize(idx);
+                    self.memory.fetch_add(allocated, Ordering::Relaxed);
+                    edge
+                }
+                None => {
+                    assert!(c_node.is_terminal());
+                    job.node_path.push(c_node);
+                    break;
+                }
+            };
+
+            c_edge.add_virtual_loss();
+            job.history.make_move(c_edge.mov);
+            let board = job.history.board();
+
+            let child = match c_edge.child_index() {
+                Some(child) => Some(child),
+                None => self.transposition(&board).map(|node| c_edge.link(node)),
+            };
+            job.node_path.push(c_node);
+
+            // game-over positions are scored here, without the network
+            let terminal = match board.status() {
+                BoardStatus::Checkmate => Some(Proven::Loss(0)),
+                BoardStatus::Stalemate => Some(Proven::Draw),
+                BoardStatus::Ongoing if job.history.is_draw() => Some(Proven::Draw),
+                BoardStatus::Ongoing => None,
+            };
+            // draws by repetition or the fifty-move rule depend on how the
+            // position was reached, and other paths may share its node
+            let depends_on_path = board.status() == BoardStatus::Ongoing && !is_insufficient_material(&board);
+
+            // a transposition leading back into the current line is a
+            // repetition, so following transpositions cannot loop
+            match (child.map(|child| self.nodes.get(child)), terminal) {
+                // repetitions depend on the path, so the draw is scored even if
+                // the child was reached as a regular position before, and only
+                // for this rollout: a stored result would leak into other paths
+                (child, Some(result)) => {
+                    if child.is_none() && !depends_on_path {
+                        self.expand(c_edge, Node::terminal(result));
+                    }
+                    job.terminal = Some(1.0 - result.q().unwrap());
+                    break;
+                }
+                // no need to search below a solved position
+                (Some(child), None) if child.proven() != Proven::Unknown => {
+                    job.terminal = Some(1.0 - child.proven().q().unwrap());
+                    break;
+                }
+                (Some(child), None) => c_node = child,
+                (None, None) => break,
             }
 
-            c_node = c_edge.child.as_ref().unwrap().clone();
             is_root = false;
         }
     }
 
-    pub fn parallel_rollouts(
-        &mut self,
-        board: Board,
-        network: &tch::CModule,
+    pub fn parallel_rollouts<E: Evaluator + ?Sized>(
+        &self,
+        history: &History,
+        evaluator: &E,
+        cache: &NNCache,
         count: usize,
-
-        #[allow(unused)]
-        child: Option<&mut Child>,
     ) {
         let mut results = vec![];
         for _ in 0..count {
-            let root_node = self.root_node().clone();
-            let mut job = Job::new(board);
-            Self::select_task(
-                root_node,
-                &mut job.board,
-                &mut job.node_path,
-                &mut job.edge_path,
-            );
+            let mut job = Job::new(history.clone());
+            self.select_task(&mut job);
             results.push(job);
         }
 
-        let mut boards = vec![];
+        // answer what we can from the cache and batch the rest for the evaluator
+        let mut evaluations: Vec<Option<Evaluation>> = vec![];
+        let mut positions = vec![];
         for result in results.iter() {
-            boards.push(result.board);
+            let evaluation = if result.needs_evaluation() {
+                let evaluation = cache.get(evaluator.cache_key(&result.history));
+                if evaluation.is_none() {
+                    positions.push(result.history.clone());
+                }
+                evaluation
+            } else {
+                None
+            };
+            evaluations.push(evaluation);
         }
 
-        let mut output = get_neural_output_batched(&boards, &network);
-
-        #[cfg(feature = "use-external-eval")]
-        let child = child.unwrap();
+        if !positions.is_empty() {
+            let mut outputs = evaluator.evaluate(&positions).into_iter();
+            for (result, evaluation) in results.iter().zip(evaluations.iter_mut()) {
+                if result.needs_evaluation() && evaluation.is_none() {
+                    let mut output = outputs.next().unwrap();
+                    cache::normalize(&mut output.0);
+                    cache.insert(evaluator.cache_key(&result.history), output.clone());
+                    *evaluation = Some(output);
+                }
+            }
+        }
 
-        for (job, output) in results.iter().zip(output.iter_mut()) {
-            let edges = job.edge_path.len();
-            let edge = &job.edge_path[edges - 1];
-            let board = job.board;
+        for (job, output) in results.iter().zip(evaluations) {
+            let edge = job.leaf_edge();
+            let board = job.board();
             let mut new_q;
 
-            if let Some(edge) = edge {
-                // get the value
-                let value = match board.status() {
-                    BoardStatus::Checkmate => {
-                        let mut winner = if board.side_to_move() == Color::White {
-                            -1.0
-                        } else {
-                            1.0
-                        };
-
-                        if board.side_to_move() != Color::White {
-                            winner *= -1.0;
-                        }
-
-                        winner
-                    }
-                    BoardStatus::Stalemate => 0.0,
-                    #[cfg(feature = "use-external-eval")]
-                    BoardStatus::Ongoing => {
-                        use std::io::{Read, Write};
-                        use vampirc_uci::UciInfoAttribute;
-
-                        let stdin = child.stdin.as_mut().unwrap();
-                        let stdout = child.stdout.as_mut().unwrap();
-                        stdin
-                            .write_all(format!("position fen {}\n", board.to_string()).as_bytes())
-                            .expect("Failed to write to stdin");
-                        stdin
-                            .write_all("go movetime 25\n".as_bytes())
-                            .expect("Failed to write to stdin");
-
-                        let mut last_value = 0.0;
-                        loop {
-                            let mut bytes = vec![];
-                            loop {
-                                // read a char
-                                let mut output = [0];
-                                stdout
-                                    .read_exact(&mut output)
-                                    .expect("Failed to read output");
-                                if output[0] as char == '\n' {
-                                    break;
-                                }
-                                bytes.push(output[0]);
-                            }
-                            let output = String::from_utf8_lossy(&bytes);
-                            let msg = parse_one(&output);
-                            match msg {
-                                UciMessage::Info(attrs) => {
-                                    for attr in attrs {
-                                        match attr {
-                                            UciInfoAttribute::Score { cp, mate, .. } => {
-                                                if let Some(cp) = cp {
-                                                    last_value = 2.0
-                                                        * (1.0
-                                                            / (1.0
-                                                                + 10.0f32.powf(
-                                                                    -(cp as f32 / 100.0) / 4.0,
-                                                                )))
-                                                        - 1.0;
-                                                } else if let Some(mate) = mate {
-                                                    if mate > 0 {
-                                                        last_value =
-                                                            1.0 - (mate.abs() as f32 * 0.01);
-                                                    } else {
-                                                        last_value =
-                                                            -1.0 + (mate.abs() as f32 * 0.01);
-                                                    }
-                                                    stdin
-                                                        .write_all("stop\n".as_bytes())
-                                                        .expect("Failed to write to stdin");
-                                                    break;
-                                                }
-                                            }
-                                            _ => {}
-                                        }
-                                    }
-                                }
-                                UciMessage::BestMove { .. } => break,
-                                _ => {}
-                            }
-                        }
-
-                        last_value
-                    }
-
-                    #[cfg(not(feature = "use-external-eval"))]
-                    BoardStatus::Ongoing => output.1,
-                };
-
-                new_q = value / 2.0 + 0.5;
-                let is_unexpanded = edge.borrow_mut().expand(new_q, &mut output.0);
+            if let Some(terminal) = job.terminal {
+                new_q = terminal;
+            } else if let Some(edge) = edge {
+                let mut output = output.unwrap();
+                // game-over positions never reach the evaluator
+                new_q = output.1 / 2.0 + 0.5;
+                let is_unexpanded = self.expand(edge, Node::new(new_q, &mut output.0));
 
                 if !is_unexpanded {
-                    self.same_paths += 1;
+                    self.same_paths.fetch_add(1, Ordering::Relaxed);
+                } else if let Some(transpositions) = &self.transpositions {
+                    let child = edge.child_index().unwrap();
+                    let previous = transpositions.lock().unwrap().insert(board.get_hash(), child);
+                    if previous.is_none() {
+                        self.memory.fetch_add(TRANSPOSITION_ENTRY, Ordering::Relaxed);
+                    }
                 }
                 new_q = 1. - new_q
             } else {
-                let mut winner = match board.status() {
-                    BoardStatus::Checkmate => {
-                        if board.side_to_move() == Color::White {
-                            -1
-                        } else {
-                            1
-                        }
-                    }
-                    BoardStatus::Stalemate => 0,
-                    BoardStatus::Ongoing => unreachable!(),
-                };
-
-                if board.side_to_move() != Color::White {
-                    winner *= -1;
-                }
-
-                new_q = winner as f32 / 2. + 0.5;
+                new_q = job.node_path.last().unwrap().proven().q().unwrap();
             }
 
-            self.depth = self.depth.max(job.node_path.len());
+            self.seldepth.fetch_max(job.node_path.len(), Ordering::Relaxed);
+            self.depth_sum.fetch_add(job.node_path.len(), Ordering::Relaxed);
+            self.depth_count.fetch_add(1, Ordering::Relaxed);
 
             let last_node_idx = job.node_path.len() - 1;
-            for i in (0..=last_node_idx).rev() {
-                let node = &job.node_path[i];
-                let mut node = node.borrow_mut();
-                node.n += 1.0;
-                if (last_node_idx - i) % 2 == 0 {
-                    node.sum_q += new_q;
-                } else {
-                    node.sum_q += 1.0 - new_q;
+            for (i, (node, edge)) in job.node_path.iter().zip(job.edge_path.iter()).enumerate().rev() {
+                let q = if (last_node_idx - i) % 2 == 0 { new_q } else { 1.0 - new_q };
+                node.update(q);
+                if let Some(edge) = edge {
+                    node.edge(*edge).update(q);
                 }
             }
 
-            for edge in job.edge_path.iter() {
-                if let Some(edge) = edge {
-                    edge.borrow_mut().clear_virtual_loss();
+            // a newly proven position can settle its ancestors as well
+            for node in job.node_path.iter().rev() {
+                if !node.update_proven(&self.nodes) {
+                    break;
                 }
             }
         }
     }
 }
 
-pub struct Job {
-    board: Board,
-    node_path: Vec<Rc<RefCell<Node>>>,
-    edge_path: Vec<Option<Rc<RefCell<Edge>>>>,
+pub struct Job<'a> {
+    history: History,
+    node_path: Vec<&'a Node>,
+    edge_path: Vec<Option<usize>>,
+    // set when the rollout ended without reaching a leaf that needs the
+    // network, as q for the side to move at the last node in `node_path`
+    terminal: Option<f32>,
 }
 
-impl Job {
-    pub fn new(board: Board) -> Self {
+impl<'a> Job<'a> {
+    pub fn new(history: History) -> Self {
         Self {
-            board,
+            history,
             node_path: vec![],
             edge_path: vec![],
+            terminal: None,
+        }
+    }
+
+    pub fn board(&self) -> Board {
+        self.history.board()
+    }
+
+    pub fn needs_evaluation(&self) -> bool {
+        self.terminal.is_none() && self.leaf_edge().is_some()
+    }
+
+    /// The edge this rollout stopped on, or `None` if it ended on a terminal node.
+    pub fn leaf_edge(&self) -> Option<&'a Edge> {
+        let node = *self.node_path.last()?;
+        self.edge_path.last()?.map(|idx| node.edge(idx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::MockEvaluator;
+
+    use std::str::FromStr;
+
+    #[test]
+    fn repetition_edges_score_as_draws() {
+        let mut history = History::new(Board::default(), 0);
+        for mov in ["g1f3", "g8f6", "f3g1", "f6g8", "g1f3", "g8f6"] {
+            history.make_move(ChessMove::from_str(mov).unwrap());
+        }
+
+        let cache = NNCache::new(0);
+        let root = Root::new(&history, &MockEvaluator, false);
+        for _ in 0..200 {
+            root.parallel_rollouts(&history, &MockEvaluator, &cache, 16);
+        }
+
+        let repetition = ChessMove::from_str("f3g1").unwrap();
+        let edge = root.root_node().edges().find(|edge| edge.mov == repetition).unwrap();
+        assert!(edge.child(root.nodes()).is_none());
+        assert!(edge.get_n() > 0.0);
+        assert_eq!(edge.get_q(root.nodes()), 0.5);
+    }
+
+    #[test]
+    fn principal_variation_ends_on_transposition_cycles() {
+        let history = History::from_fen("8/8/4k3/8/8/4K3/8/R6r w - - 0 1").unwrap();
+        let cache = NNCache::new(0);
+        let root = Root::new(&history, &MockEvaluator, true);
+        for _ in 0..200 {
+            root.parallel_rollouts(&history, &MockEvaluator, &cache, 16);
+        }
+
+        // every node appears at most once on a line
+        let pv = root.principal_variation(root.root_index(), &[]);
+        assert!(!pv.is_empty() && pv.len() <= root.node_count());
+        for edge in root.root_node().edges() {
+            if let Some(child) = edge.child_index() {
+                assert!(root.principal_variation(child, &[]).len() <= root.node_count());
+            }
+        }
+    }
+
+    #[test]
+    fn terminal_subtrees_are_not_promoted() {
+        let history = History::from_fen("8/8/8/4k3/8/8/3p4/4K1N1 w - - 0 1").unwrap();
+        let cache = NNCache::new(0);
+        let root = Root::new(&history, &MockEvaluator, false);
+        for _ in 0..25 {
+            root.parallel_rollouts(&history, &MockEvaluator, &cache, 16);
+        }
+
+        // taking the last pawn leaves too little material to mate
+        let capture = ChessMove::from_str("e1d2").unwrap();
+        let edge = root.root_node().edges().find(|edge| edge.mov == capture).unwrap();
+        assert!(edge.child(root.nodes()).is_some_and(|child| child.is_terminal()));
+
+        let board = history.board();
+        assert!(root.promote(board, board.make_move_new(capture), 2).is_none());
+    }
+
+    #[test]
+    fn search_moves_decide_the_root_result() {
+        let history = History::from_fen("6k1/5ppp/8/8/8/8/8/R5K1 w - - 0 1").unwrap();
+        let cache = NNCache::new(0);
+        let mut root = Root::new(&history, &MockEvaluator, false);
+        for _ in 0..25 {
+            root.parallel_rollouts(&history, &MockEvaluator, &cache, 16);
         }
+        assert_eq!(root.proven(), Proven::Win(1));
+
+        // as in a reused tree, the mate was found through a move not searched now
+        let moves = ["g1f1", "g1g2"].map(|mov| ChessMove::from_str(mov).unwrap());
+        root.set_search_moves(moves.to_vec());
+        assert_eq!(root.root_node().proven(), Proven::Win(1));
+        assert_eq!(root.proven(), Proven::Unknown);
+        assert!(!root.is_decided(1000.0));
+        assert!(root.get_q() < 1.0);
     }
 }
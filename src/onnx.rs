@@ -0,0 +1,109 @@
+use crate::encoding::{check_outputs, legal_move_masks, probe_format, NetworkFormat, Outputs};
+use crate::history::History;
+
+use tract_onnx::prelude::*;
+use tract_onnx::tract_hir::infer::Factoid;
+
+type Plan = TypedSimplePlan<TypedModel>;
+
+/// An ONNX network taking the encoded positions and legal move masks, like a
+/// TorchScript one, and run with tract. Its first dimension must be the batch.
+pub struct Network {
+    plan: Plan,
+    // the type the network declares for the masks, which exporters differ on
+    mask_type: DatumType,
+    format: NetworkFormat,
+}
+
+/// Specialize `model` for the input shapes of `format`, with a symbolic batch size.
+fn plan(model: &InferenceModel, format: NetworkFormat, mask_type: DatumType) -> TractResult<Plan> {
+    let batch = model.symbol_table.sym("N").to_dim();
+    let shape = |planes: usize| tvec![batch.clone(), planes.to_dim(), 8.to_dim(), 8.to_dim()];
+    model
+        .clone()
+        .with_input_fact(0, InferenceFact::dt_shape(f32::datum_type(), shape(format.input.planes())))?
+        .with_input_fact(1, InferenceFact::dt_shape(mask_type, shape(format.policy.planes())))?
+        // the shapes the file gives the outputs may name the batch differently
+        .with_output_fact(0, InferenceFact::default())?
+        .with_output_fact(1, InferenceFact::default())?
+        .into_optimized()?
+        .into_runnable()
+}
+
+/// Run `plan` on a batch, returning the value and the flattened policy of
+/// each position. Fails if the network does not take inputs shaped for `format`.
+fn forward(plan: &Plan, mask_type: DatumType, histories: &[History], format: NetworkFormat) -> Result<Outputs, String> {
+    let boards = histories.iter().map(History::board).collect::<Vec<_>>();
+    let positions = Tensor::from(format.input.encode(histories));
+    let masks = Tensor::from(legal_move_masks(&boards, format.policy))
+        .cast_to_dt(mask_type)
+        .map_err(|err| err.to_string())?
+        .into_owned();
+
+    let outputs = plan
+        .run(tvec![positions.into_tvalue(), masks.into_tvalue()])
+        .map_err(|err| err.to_string())?;
+    if outputs.len() != 2 {
+        return Err("network does not return a value and a policy".to_string());
+    }
+
+    let flatten = |output: &TValue| -> Result<ndarray::ArrayD<f32>, String> {
+        let output = output.cast_to::<f32>().map_err(|err| err.to_string())?;
+        let output = output.to_array_view::<f32>().map_err(|err| err.to_string())?;
+        let entries = output.len() / histories.len();
+        output
+            .to_owned()
+            .into_shape(vec![histories.len(), entries])
+            .map_err(|err| err.to_string())
+    };
+    let value = flatten(&outputs[0])?;
+    let policy = flatten(&outputs[1])?.mapv(|p| if p.is_finite() { p } else { 0.0 });
+
+    check_outputs((value, policy), format)
+}
+
+impl Network {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let model = tract_onnx::onnx()
+            .model_for_path(path)
+            .map_err(|err| format!("'{}' is not a valid ONNX model: {}", path, err))?;
+        if model.inputs.len() != 2 || model.outputs.len() != 2 {
+            return Err(format!(
+                "'{}' has {} inputs and {} outputs instead of (positions, masks) and (value, policy)",
+                path,
+                model.inputs.len(),
+                model.outputs.len()
+            ));
+        }
+        let mask_type = model
+            .input_fact(1)
+            .ok()
+            .and_then(|fact| fact.datum_type.concretize())
+            .unwrap_or(DatumType::I32);
+
+        // keep the plan that worked rather than optimizing the network again
+        let mut found = None;
+        let format = probe_format(|histories, format| {
+            let plan = plan(&model, format, mask_type).map_err(|err| err.to_string())?;
+            let outputs = forward(&plan, mask_type, histories, format)?;
+            found = Some(plan);
+            Ok(outputs)
+        })
+        .map_err(|err| format!("'{}' does not take the inputs of any known format ({})", path, err))?;
+
+        Ok(Self {
+            plan: found.unwrap(),
+            mask_type,
+            format,
+        })
+    }
+
+    pub fn format(&self) -> NetworkFormat {
+        self.format
+    }
+
+    pub fn forward(&self, histories: &[History]) -> Result<Outputs, String> {
+        forward(&self.plan, self.mask_type, histories, self.format)
+    }
+}
+
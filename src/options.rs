@@ -0,0 +1,358 @@
+use crate::config::*;
+use crate::eval::Blend;
+use crate::uci_engine::Limits;
+
+use std::time::Duration;
+
+/// The type of a UCI option, with its default and, for spins, its bounds.
+#[derive(Debug)]
+pub enum Kind {
+    Check { default: bool },
+    Spin { default: i64, min: i64, max: i64 },
+    String { default: &'static str },
+    Combo { default: &'static str, vars: &'static [&'static str] },
+}
+
+#[derive(Debug)]
+pub struct UciOption {
+    pub name: &'static str,
+    pub kind: Kind,
+}
+
+impl UciOption {
+    /// The `option` line announced in reply to `uci`.
+    pub fn declaration(&self) -> String {
+        match &self.kind {
+            Kind::Check { default } => {
+                format!("option name {} type check default {}", self.name, default)
+            }
+            Kind::Spin { default, min, max } => format!(
+                "option name {} type spin default {} min {} max {}",
+                self.name, default, min, max
+            ),
+            Kind::String { default } => {
+                let default = if default.is_empty() { "<empty>" } else { default };
+                format!("option name {} type string default {}", self.name, default)
+            }
+            Kind::Combo { default, vars } => {
+                let mut declaration = format!("option name {} type combo default {}", self.name, default);
+                for var in vars.iter() {
+                    declaration += &format!(" var {}", var);
+                }
+                declaration
+            }
+        }
+    }
+
+    fn default_value(&self) -> Value {
+        match self.kind {
+            Kind::Check { default } => Value::Check(default),
+            Kind::Spin { default, .. } => Value::Spin(default),
+            Kind::String { default } | Kind::Combo { default, .. } => {
+                Value::String(default.to_string())
+            }
+        }
+    }
+
+    /// Parse a `setoption` value, clamping spins into their bounds.
+    fn parse(&self, value: &str) -> Option<Value> {
+        let value = value.trim();
+        match self.kind {
+            Kind::Check { .. } => match value.to_ascii_lowercase().as_str() {
+                "true" => Some(Value::Check(true)),
+                "false" => Some(Value::Check(false)),
+                _ => None,
+            },
+            Kind::Spin { min, max, .. } => {
+                value.parse::<i64>().ok().map(|value| Value::Spin(value.clamp(min, max)))
+            }
+            Kind::String { .. } => {
+                let value = if value == "<empty>" { "" } else { value };
+                Some(Value::String(value.to_string()))
+            }
+            Kind::Combo { vars, .. } => vars
+                .iter()
+                .find(|var| var.eq_ignore_ascii_case(value))
+                .map(|var| Value::String(var.to_string())),
+        }
+    }
+}
+
+/// Every option the engine understands, in the order they are announced.
+/// `CPuct` is a spin in hundredths, as UCI has no fractional option type, and
+/// `ExternalWeight` and `ExternalFraction` are percentages. An empty
+/// `WeightsFile` means the network chosen on the command line or in the
+/// environment at startup.
+pub const OPTIONS: &[UciOption] = &[
+    UciOption {
+        name: "WeightsFile",
+        kind: Kind::String { default: "" },
+    },
+    UciOption {
+        name: "Evaluator",
+        kind: Kind::Combo {
+            default: "Network",
+            vars: &["Network", "External", "Mock"],
+        },
+    },
+    UciOption {
+        name: "ExternalEngine",
+        kind: Kind::String { default: ENGINE },
+    },
+    UciOption {
+        name: "ExternalMoveTime",
+        kind: Kind::Spin {
+            default: DEFAULT_EXTERNAL_MOVETIME as i64,
+            min: 0,
+            max: 60000,
+        },
+    },
+    UciOption {
+        name: "ExternalNodes",
+        kind: Kind::Spin {
+            default: DEFAULT_EXTERNAL_NODES as i64,
+            min: 0,
+            max: 1 << 40,
+        },
+    },
+    UciOption {
+        name: "ExternalDepth",
+        kind: Kind::Spin {
+            default: DEFAULT_EXTERNAL_DEPTH as i64,
+            min: 0,
+            max: 255,
+        },
+    },
+    UciOption {
+        name: "ExternalWeight",
+        kind: Kind::Spin {
+            default: DEFAULT_EXTERNAL_WEIGHT as i64,
+            min: 0,
+            max: 100,
+        },
+    },
+    UciOption {
+        name: "ExternalFraction",
+        kind: Kind::Spin {
+            default: DEFAULT_EXTERNAL_FRACTION as i64,
+            min: 0,
+            max: 100,
+        },
+    },
+    UciOption {
+        name: "Threads",
+        kind: Kind::Spin {
+            default: DEFAULT_THREADS as i64,
+            min: 1,
+            max: MAX_THREADS as i64,
+        },
+    },
+    UciOption {
+        name: "TorchThreads",
+        kind: Kind::Spin {
+            default: DEFAULT_TORCH_THREADS as i64,
+            min: 0,
+            max: MAX_THREADS as i64,
+        },
+    },
+    UciOption {
+        name: "BatchSize",
+        kind: Kind::Spin {
+            default: DEFAULT_BATCH_SIZE as i64,
+            min: 1,
+            max: MAX_BATCH_SIZE as i64,
+        },
+    },
+    UciOption {
+        name: "CPuct",
+        kind: Kind::Spin {
+            default: (DEFAULT_CPUCT * 100.0) as i64,
+            min: 0,
+            max: 10000,
+        },
+    },
+    UciOption {
+        name: "Transpositions",
+        kind: Kind::Check {
+            default: DEFAULT_TRANSPOSITIONS,
+        },
+    },
+    UciOption {
+        name: "Hash",
+        kind: Kind::Spin {
+            default: DEFAULT_HASH as i64,
+            min: 1,
+            max: MAX_HASH as i64,
+        },
+    },
+    UciOption {
+        name: "NNCacheSize",
+        kind: Kind::Spin {
+            default: DEFAULT_NN_CACHE_SIZE as i64,
+            min: 0,
+            max: MAX_NN_CACHE_SIZE as i64,
+        },
+    },
+    UciOption {
+        name: "MoveOverhead",
+        kind: Kind::Spin {
+            default: DEFAULT_MOVE_OVERHEAD as i64,
+            min: 0,
+            max: MAX_MOVE_OVERHEAD as i64,
+        },
+    },
+    UciOption {
+        name: "SmartPruning",
+        kind: Kind::Check {
+            default: DEFAULT_SMART_PRUNING,
+        },
+    },
+    UciOption {
+        name: "Ponder",
+        kind: Kind::Check { default: false },
+    },
+    UciOption {
+        name: "MultiPV",
+        kind: Kind::Spin {
+            default: DEFAULT_MULTIPV as i64,
+            min: 1,
+            max: MAX_MULTIPV as i64,
+        },
+    },
+];
+
+#[derive(Clone, Debug)]
+enum Value {
+    Check(bool),
+    Spin(i64),
+    String(String),
+}
+
+/// The current value of every option in `OPTIONS`. The UCI loop updates it on
+/// `setoption` and hands a copy to the search with each `go`.
+#[derive(Clone, Debug)]
+pub struct Options {
+    values: Vec<Value>,
+}
+
+impl Options {
+    pub fn new() -> Self {
+        Self {
+            values: OPTIONS.iter().map(UciOption::default_value).collect(),
+        }
+    }
+
+    /// Handle `setoption name <name> value <value>`. Option names are case insensitive.
+    pub fn set(&mut self, name: &str, value: Option<&str>) -> Result<(), String> {
+        let index = OPTIONS
+            .iter()
+            .position(|option| option.name.eq_ignore_ascii_case(name.trim()))
+            .ok_or_else(|| format!("unknown option {}", name))?;
+        let option = &OPTIONS[index];
+
+        self.values[index] = value
+            .and_then(|value| option.parse(value))
+            .ok_or_else(|| format!("invalid value for option {}", option.name))?;
+        Ok(())
+    }
+
+    fn value(&self, name: &str) -> &Value {
+        let index = OPTIONS
+            .iter()
+            .position(|option| option.name == name)
+            .unwrap_or_else(|| panic!("no option named {}", name));
+        &self.values[index]
+    }
+
+    pub fn check(&self, name: &str) -> bool {
+        match self.value(name) {
+            Value::Check(value) => *value,
+            value => panic!("option {} is not a check: {:?}", name, value),
+        }
+    }
+
+    pub fn spin(&self, name: &str) -> i64 {
+        match self.value(name) {
+            Value::Spin(value) => *value,
+            value => panic!("option {} is not a spin: {:?}", name, value),
+        }
+    }
+
+    pub fn string(&self, name: &str) -> &str {
+        match self.value(name) {
+            Value::String(value) => value,
+            value => panic!("option {} is not a string: {:?}", name, value),
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.spin("Threads") as usize
+    }
+
+    #[cfg(feature = "torch")]
+    pub fn torch_threads(&self) -> usize {
+        self.spin("TorchThreads") as usize
+    }
+
+    pub fn batch_size(&self) -> usize {
+        self.spin("BatchSize") as usize
+    }
+
+    pub fn cpuct(&self) -> f32 {
+        self.spin("CPuct") as f32 / 100.0
+    }
+
+    pub fn transpositions(&self) -> bool {
+        self.check("Transpositions")
+    }
+
+    pub fn hash(&self) -> usize {
+        self.spin("Hash") as usize
+    }
+
+    pub fn nn_cache_size(&self) -> usize {
+        self.spin("NNCacheSize") as usize
+    }
+
+    pub fn move_overhead(&self) -> u64 {
+        self.spin("MoveOverhead") as u64
+    }
+
+    pub fn smart_pruning(&self) -> bool {
+        self.check("SmartPruning")
+    }
+
+    pub fn multipv(&self) -> usize {
+        self.spin("MultiPV") as usize
+    }
+
+    pub fn weights_file(&self) -> &str {
+        self.string("WeightsFile")
+    }
+
+    pub fn evaluator(&self) -> &str {
+        self.string("Evaluator")
+    }
+
+    pub fn external_engine(&self) -> &str {
+        self.string("ExternalEngine")
+    }
+
+    /// The search limits for the external engine, leaving out those set to 0.
+    pub fn external_limits(&self) -> Limits {
+        let limit = |name| Some(self.spin(name) as u64).filter(|value| *value > 0);
+        Limits {
+            movetime: limit("ExternalMoveTime").map(Duration::from_millis),
+            nodes: limit("ExternalNodes"),
+            depth: limit("ExternalDepth").map(|depth| depth as u8),
+        }
+    }
+
+    /// How the external evaluator mixes its value in, from the percentage options.
+    pub fn external_blend(&self) -> Blend {
+        Blend {
+            weight: self.spin("ExternalWeight") as f32 / 100.0,
+            fraction: self.spin("ExternalFraction") as f32 / 100.0,
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use chess::Color;
+use vampirc_uci::UciTimeControl;
+
+use std::time::Duration;
+
+/// Moves the remaining time is spread over when the GUI sends no `movestogo`.
+const DEFAULT_MOVES_TO_GO: u32 = 30;
+/// Share of the increment spent on top of the base allocation.
+const INCREMENT_USAGE: f32 = 0.75;
+/// How far past the soft limit an unstable search may run.
+const HARD_FACTOR: u32 = 3;
+/// Most of the usable clock a single move may take, leaving a margin for the
+/// moves after it; the last move before a time control may use nearly all of it.
+const MAX_USAGE: f32 = 0.5;
+const MAX_USAGE_LAST_MOVE: f32 = 0.9;
+/// Share of the soft limit the best move must have held for before stopping.
+const STABLE_FRACTION: f32 = 0.25;
+
+fn to_std(duration: Option<vampirc_uci::Duration>) -> Duration {
+    duration
+        .and_then(|duration| duration.to_std().ok())
+        .unwrap_or_default()
+}
+
+/// How long one search may take. The search stops once `soft` has passed and
+/// the best move has been stable for a while, and in any case at `hard`.
+#[derive(Clone, Copy, Debug)]
+pub struct TimeBudget {
+    pub soft: Duration,
+    pub hard: Duration,
+}
+
+impl TimeBudget {
+    pub fn fixed(duration: Duration) -> Self {
+        Self {
+            soft: duration,
+            hard: duration,
+        }
+    }
+
+    /// The budget for `time_control` with `side` to move, or `None` for a
+    /// search without a time limit. `move_overhead` is taken off every
+    /// allocation to cover the communication delays with the GUI.
+    pub fn new(time_control: &UciTimeControl, side: Color, move_overhead: Duration) -> Option<Self> {
+        match time_control {
+            UciTimeControl::MoveTime(duration) => {
+                Some(Self::fixed(to_std(Some(*duration)).saturating_sub(move_overhead)))
+            }
+            UciTimeControl::TimeLeft {
+                white_time,
+                black_time,
+                white_increment,
+                black_increment,
+                moves_to_go,
+            } => {
+                // a missing clock is treated as an empty one
+                let (time, increment) = match side {
+                    Color::White => (to_std(*white_time), to_std(*white_increment)),
+                    Color::Black => (to_std(*black_time), to_std(*black_increment)),
+                };
+                let moves_to_go = moves_to_go.map_or(DEFAULT_MOVES_TO_GO, |moves| moves.max(1) as u32);
+
+                let usable = time.saturating_sub(move_overhead);
+                let max_usage = if moves_to_go == 1 { MAX_USAGE_LAST_MOVE } else { MAX_USAGE };
+                let max = usable.mul_f32(max_usage);
+
+                let soft = usable / moves_to_go + increment.mul_f32(INCREMENT_USAGE);
+                let hard = (soft * HARD_FACTOR).min(max);
+                Some(Self {
+                    soft: soft.min(hard),
+                    hard,
+                })
+            }
+            UciTimeControl::Infinite | UciTimeControl::Ponder => None,
+        }
+    }
+
+    /// Whether a search that has run for `elapsed`, with the best move unchanged
+    /// for the last `stable_for`, should stop.
+    pub fn is_over(&self, elapsed: Duration, stable_for: Duration) -> bool {
+        elapsed >= self.hard
+            || (elapsed >= self.soft && stable_for >= self.soft.mul_f32(STABLE_FRACTION))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: i64) -> Option<vampirc_uci::Duration> {
+        Some(vampirc_uci::Duration::milliseconds(millis))
+    }
+
+    fn clock(time: i64, increment: i64, moves_to_go: Option<u8>) -> UciTimeControl {
+        UciTimeControl::TimeLeft {
+            white_time: ms(time),
+            black_time: ms(time),
+            white_increment: ms(increment),
+            black_increment: ms(increment),
+            moves_to_go,
+        }
+    }
+
+    #[test]
+    fn black_uses_its_own_increment() {
+        let time_control = UciTimeControl::TimeLeft {
+            white_time: ms(60000),
+            black_time: ms(60000),
+            white_increment: ms(0),
+            black_increment: ms(10000),
+            moves_to_go: None,
+        };
+        let white = TimeBudget::new(&time_control, Color::White, Duration::ZERO).unwrap();
+        let black = TimeBudget::new(&time_control, Color::Black, Duration::ZERO).unwrap();
+        assert_eq!(white.soft, Duration::from_millis(2000));
+        assert_eq!(black.soft, Duration::from_millis(2000) + Duration::from_millis(10000).mul_f32(INCREMENT_USAGE));
+    }
+
+    #[test]
+    fn missing_clock_is_empty() {
+        let time_control = UciTimeControl::TimeLeft {
+            white_time: None,
+            black_time: ms(60000),
+            white_increment: None,
+            black_increment: None,
+            moves_to_go: None,
+        };
+        let budget = TimeBudget::new(&time_control, Color::White, Duration::from_millis(10)).unwrap();
+        assert_eq!(budget.hard, Duration::ZERO);
+    }
+
+    #[test]
+    fn last_move_before_time_control_may_use_more() {
+        let budget = TimeBudget::new(&clock(10000, 0, Some(1)), Color::White, Duration::ZERO).unwrap();
+        assert_eq!(budget.hard, Duration::from_millis(10000).mul_f32(MAX_USAGE_LAST_MOVE));
+
+        let budget = TimeBudget::new(&clock(10000, 0, Some(2)), Color::White, Duration::ZERO).unwrap();
+        assert_eq!(budget.hard, Duration::from_millis(10000).mul_f32(MAX_USAGE));
+    }
+
+    #[test]
+    fn overhead_beyond_the_clock_leaves_no_time() {
+        let overhead = Duration::from_millis(100);
+        let budget = TimeBudget::new(&clock(50, 0, None), Color::White, overhead).unwrap();
+        assert_eq!((budget.soft, budget.hard), (Duration::ZERO, Duration::ZERO));
+
+        let movetime = UciTimeControl::MoveTime(vampirc_uci::Duration::milliseconds(50));
+        let budget = TimeBudget::new(&movetime, Color::White, overhead).unwrap();
+        assert_eq!((budget.soft, budget.hard), (Duration::ZERO, Duration::ZERO));
+    }
+
+    #[test]
+    fn soft_limit_is_within_hard_limit() {
+        for time in [0, 100, 1000, 10000, 60000, 600000] {
+            for increment in [0, 100, 1000, 10000, 60000] {
+                for moves_to_go in [None, Some(1), Some(2), Some(40)] {
+                    for side in [Color::White, Color::Black] {
+                        let budget = TimeBudget::new(&clock(time, increment, moves_to_go), side, Duration::from_millis(30))
+                            .unwrap();
+                        assert!(
+                            budget.soft <= budget.hard,
+                            "{:?} with {} ms + {} ms and {:?} moves to go",
+                            budget,
+                            time,
+                            increment,
+                            moves_to_go
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
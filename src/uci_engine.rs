@@ -0,0 +1,263 @@
+use chess::{Board, ChessMove};
+
+use std::fmt;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long `stop` may take to produce a `bestmove` before the engine is
+/// considered hung.
+const STOP_GRACE: Duration = Duration::from_millis(500);
+
+/// What the engine is told to search with `go`. Limits that are `None` are
+/// left out; with none at all the engine searches to depth 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    pub movetime: Option<Duration>,
+    pub nodes: Option<u64>,
+    pub depth: Option<u8>,
+}
+
+impl Limits {
+    fn go_command(&self) -> String {
+        let mut command = "go".to_string();
+        if let Some(movetime) = self.movetime {
+            command += &format!(" movetime {}", movetime.as_millis());
+        }
+        if let Some(nodes) = self.nodes {
+            command += &format!(" nodes {}", nodes);
+        }
+        if let Some(depth) = self.depth {
+            command += &format!(" depth {}", depth);
+        }
+        if command == "go" {
+            command += " depth 1";
+        }
+        command
+    }
+}
+
+/// An engine's verdict on a position, for the side to move.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Score {
+    Cp(i32),
+    /// Mate in this many moves, negative when the side to move gets mated.
+    Mate(i32),
+}
+
+impl Score {
+    /// The score as a value in [-1, 1]. Centipawns follow the logistic curve
+    /// used for the network's training targets; mates are certain results.
+    pub fn value(self) -> f32 {
+        match self {
+            Score::Cp(cp) => 2.0 / (1.0 + 10.0f32.powf(-(cp as f32) / 400.0)) - 1.0,
+            Score::Mate(moves) if moves > 0 => 1.0,
+            // `mate 0` is sent for a position where the side to move is mated
+            Score::Mate(_) => -1.0,
+        }
+    }
+
+    /// Read the score from an `info` line, skipping bounds from failed
+    /// aspiration windows.
+    fn parse(line: &str) -> Option<Self> {
+        let tokens = line.split_whitespace().collect::<Vec<_>>();
+        let at = tokens.iter().position(|token| *token == "score")?;
+        let bound = tokens
+            .get(at + 3)
+            .is_some_and(|token| *token == "lowerbound" || *token == "upperbound");
+        if bound {
+            return None;
+        }
+
+        let value = tokens.get(at + 2)?.parse().ok()?;
+        match *tokens.get(at + 1)? {
+            "cp" => Some(Score::Cp(value)),
+            "mate" => Some(Score::Mate(value)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Score {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Score::Cp(cp) => write!(f, "cp {}", cp),
+            Score::Mate(moves) => write!(f, "mate {}", moves),
+        }
+    }
+}
+
+/// The result of asking the engine about a position.
+#[derive(Clone, Copy, Debug)]
+pub struct Analysis {
+    pub score: Score,
+    /// `None` when the position has no legal moves.
+    pub best_move: Option<ChessMove>,
+}
+
+struct Process {
+    child: Child,
+    stdin: ChildStdin,
+    // stdout is read on its own thread so that waiting for it can time out
+    lines: Receiver<String>,
+}
+
+impl Process {
+    fn send(&mut self, command: &str) -> Result<(), String> {
+        writeln!(self.stdin, "{}", command)
+            .and_then(|_| self.stdin.flush())
+            .map_err(|err| format!("engine stopped accepting commands: {}", err))
+    }
+
+    /// Read lines until one starts with `prefix`, handing the ones before it to `f`.
+    fn wait_for(
+        &mut self,
+        prefix: &str,
+        deadline: Instant,
+        mut f: impl FnMut(&str),
+    ) -> Result<String, String> {
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.lines.recv_timeout(timeout) {
+                Ok(line) if line.starts_with(prefix) => return Ok(line),
+                Ok(line) => f(&line),
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(format!("engine timed out waiting for '{}'", prefix))
+                }
+                Err(RecvTimeoutError::Disconnected) => return Err("engine exited".to_string()),
+            }
+        }
+    }
+
+    fn kill(mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// A client for a UCI engine running as a child process. The process is
+/// started and handshaken once and restarted when it crashes or hangs.
+pub struct UciEngine {
+    command: String,
+    timeout: Duration,
+    process: Option<Process>,
+}
+
+impl UciEngine {
+    /// Start `command` and wait for it to complete the `uci`/`isready`
+    /// handshake. `timeout` bounds every wait beyond the search's own movetime.
+    pub fn start(command: &str, timeout: Duration) -> Result<Self, String> {
+        let mut engine = Self {
+            command: command.to_string(),
+            timeout,
+            process: None,
+        };
+        engine.ensure_started()?;
+        Ok(engine)
+    }
+
+    fn spawn(&self) -> Result<Process, String> {
+        let mut child = Command::new(&self.command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|err| format!("failed to start engine '{}': {}", self.command, err))?;
+
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let (tx, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else { break };
+                if tx.send(line.trim().to_string()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut process = Process { child, stdin, lines };
+        let deadline = Instant::now() + self.timeout;
+        let handshake = process
+            .send("uci")
+            .and_then(|_| process.wait_for("uciok", deadline, |_| {}))
+            .and_then(|_| process.send("isready"))
+            .and_then(|_| process.wait_for("readyok", deadline, |_| {}));
+        match handshake {
+            Ok(_) => Ok(process),
+            Err(err) => {
+                process.kill();
+                Err(err)
+            }
+        }
+    }
+
+    fn ensure_started(&mut self) -> Result<&mut Process, String> {
+        let exited = match &mut self.process {
+            Some(process) => !matches!(process.child.try_wait(), Ok(None)),
+            None => true,
+        };
+        if exited {
+            if let Some(process) = self.process.take() {
+                process.kill();
+            }
+            self.process = Some(self.spawn()?);
+        }
+
+        Ok(self.process.as_mut().unwrap())
+    }
+
+    /// Search `board` within `limits` and report the final score and best move.
+    /// A crashed or hung engine is killed and started again on the next call.
+    pub fn analyse(&mut self, board: &Board, limits: Limits) -> Result<Analysis, String> {
+        let timeout = self.timeout + limits.movetime.unwrap_or_default();
+        let result = self.search(board, limits, timeout);
+        if result.is_err() {
+            if let Some(process) = self.process.take() {
+                process.kill();
+            }
+        }
+
+        result
+    }
+
+    fn search(&mut self, board: &Board, limits: Limits, timeout: Duration) -> Result<Analysis, String> {
+        let process = self.ensure_started()?;
+        process.send(&format!("position fen {}", board))?;
+        process.send(&limits.go_command())?;
+
+        let mut score = None;
+        let mut on_info = |line: &str| {
+            if line.starts_with("info") {
+                score = Score::parse(line).or(score);
+            }
+        };
+        let best_move = match process.wait_for("bestmove", Instant::now() + timeout, &mut on_info) {
+            Ok(line) => line,
+            Err(_) => {
+                // give the engine a chance to answer before giving up on it
+                process.send("stop")?;
+                process.wait_for("bestmove", Instant::now() + STOP_GRACE, &mut on_info)?
+            }
+        };
+
+        let best_move = best_move
+            .split_whitespace()
+            .nth(1)
+            .and_then(|mov| ChessMove::from_str(mov).ok());
+        let score = score.ok_or_else(|| "engine sent no score".to_string())?;
+        Ok(Analysis { score, best_move })
+    }
+}
+
+impl Drop for UciEngine {
+    fn drop(&mut self) {
+        if let Some(mut process) = self.process.take() {
+            let _ = process.send("quit");
+            process.kill();
+        }
+    }
+}